@@ -0,0 +1,102 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+
+// the integration tests use all of the mock, this only a part
+#[allow(dead_code)]
+#[path = "../tests/mock/mod.rs"]
+mod mock;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ads::{AmsAddr, AmsNetId};
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use ads_forwarder::Options;
+use ads_forwarder::client::{self, AdsConn};
+use ads_forwarder::forwarder::{Beckhoff, BhType, Forwarder};
+use ads_forwarder::util::{BECKHOFF_BC_UDP_PORT, BECKHOFF_TCP_PORT, BECKHOFF_UDP_PORT};
+
+use mock::MockBeckhoff;
+
+/// Read sizes: a single value, and a near-MTU payload.
+const SIZES: [usize; 2] = [4, 1400];
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+
+/// Start a mock Beckhoff and a forwarder in front of it, and return the
+/// addresses of both.
+fn start() -> (SocketAddr, SocketAddr, AmsNetId) {
+    // the forwarder connects to the standard ports, so give the mock its own
+    // loopback address; these differ from the ones the integration tests use
+    let bh_addr = Ipv4Addr::new(127, 0, 0, 5);
+    let mut mock = MockBeckhoff::default();
+    mock.variables.insert((0x4020, 0), vec![0x55; SIZES[1]]);
+    let netid = mock.netid;
+    let running = mock.start_on(bh_addr, BECKHOFF_BC_UDP_PORT, BECKHOFF_UDP_PORT,
+                                BECKHOFF_TCP_PORT).expect("starting mock");
+
+    let listen: SocketAddr = (Ipv4Addr::new(127, 0, 0, 6), BECKHOFF_TCP_PORT).into();
+    let listen_arg = listen.to_string();
+    let opts = Options::parse_from(["ads_forwarder", "--listen", listen_arg.as_str()]);
+    let bh = Beckhoff { if_addr: Ipv4Addr::LOCALHOST, bh_addr, netid, typ: BhType::CX3,
+                        name: "MOCK-CX".into(), version: None, rtt: None };
+    thread::spawn(move || Forwarder::new(opts, bh).run());
+    (running.tcp_addr, listen, netid)
+}
+
+fn connect(addr: SocketAddr) -> AdsConn {
+    let source = AmsAddr::new(AmsNetId::new(10, 0, 0, 9, 1, 1), 30000);
+    let start = Instant::now();
+    loop {
+        match AdsConn::connect(addr, source, TIMEOUT) {
+            Ok(conn) => return conn,
+            Err(err) if start.elapsed() > TIMEOUT => panic!("{addr} not reachable: {err}"),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
+
+/// Time a Read request and its reply, once directly to the mock as the
+/// baseline, and once through the forwarder.
+fn reads(c: &mut Criterion) {
+    let (mock_addr, fwd_addr, netid) = start();
+    let target = AmsAddr::new(netid, 851);
+    let mut group = c.benchmark_group("read");
+    // one frame each way per iteration, reported as frames per second
+    group.throughput(Throughput::Elements(1));
+    for (name, addr) in [("direct", mock_addr), ("forwarded", fwd_addr)] {
+        let mut conn = connect(addr);
+        for size in SIZES {
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, &size| {
+                b.iter(|| client::read(&mut conn, &target, 0x4020, 0, size).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reads);
+criterion_main!(benches);
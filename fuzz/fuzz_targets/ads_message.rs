@@ -0,0 +1,50 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use ads_forwarder::util::AdsMessage;
+
+
+fuzz_target!(|data: &[u8]| {
+    match AdsMessage::try_new(data.to_vec()) {
+        Ok(msg) => {
+            // none of the accessors may panic on a frame that passed the checks
+            let _ = msg.get_dest();
+            let _ = msg.get_source();
+            let _ = msg.get_command();
+            let _ = msg.is_reply();
+            let _ = msg.get_error_code();
+            let _ = msg.get_invoke_id();
+            let _ = msg.get_data();
+            let _ = msg.get_result();
+            let _ = msg.get_add_notification_reply_handle();
+            let _ = msg.get_add_notif_req_data();
+            let _ = msg.summary_line();
+        }
+        // rejecting the frame with an AdsError is fine
+        Err(_) => {}
+    }
+});
@@ -0,0 +1,150 @@
+"""Mock Beckhoff for testing the scanner and forwarder without hardware.
+
+Answers the UDP scan probes (CX identify or BC scan) and AddRoute requests,
+and on TCP replies to ReadState, ReadDeviceInfo, Read and Write requests
+with the configured data.
+
+Since the forwarder binds the ADS ports on all addresses itself, run this on
+another machine or network namespace, e.g.
+
+    python3 mock_bh.py --netid 5.1.2.3.1.1 --read 0x4020:0=01000000
+"""
+
+import argparse
+import socket
+import struct
+import threading
+
+BECKHOFF_TCP_PORT = 48898   # 0xBF02
+BECKHOFF_UDP_PORT = 48899   # 0xBF03
+BECKHOFF_UDP_PORT2 = 48847  # 0xBECF
+
+UDP_MAGIC = 0x71146603
+UDP_IDENTIFY = 1
+UDP_ADDROUTE = 6
+UDP_REPLY = 0x80000000
+TAG_STATUS = 1
+TAG_TCVERSION = 3
+TAG_COMPUTERNAME = 5
+
+BC_SCAN_REQUEST = 1
+BC_SCAN_REPLY = 0x80000001
+
+DEVINFO, READ, WRITE, GETSTATE = 1, 2, 3, 4
+ADSERR_SRVNOTSUPP = 0x701
+
+parser = argparse.ArgumentParser()
+parser.add_argument('--bind', default='0.0.0.0', help='address to bind to')
+parser.add_argument('--netid', default='5.1.2.3.1.1', help='NetID to report')
+parser.add_argument('--name', default='MOCK-CX', help='device name to report')
+parser.add_argument('--version', default='3.1.4024', help='TwinCAT version')
+parser.add_argument('--bc', action='store_true', help='behave like a BC')
+parser.add_argument('--state', type=int, default=5, help='ADS state (5 = RUN)')
+parser.add_argument('--read', action='append', default=[], metavar='IG:IO=HEX',
+                    help='data to return for a Read of index group/offset')
+args = parser.parse_args()
+
+netid = bytes(int(part) for part in args.netid.split('.'))
+major, minor, build = (int(part) for part in args.version.split('.'))
+read_data = {}
+for spec in args.read:
+    addr, data = spec.split('=')
+    ig, io = (int(part, 0) for part in addr.split(':'))
+    read_data[ig, io] = bytes.fromhex(data)
+
+
+def udp_reply(service, invoke_id, tags):
+    msg = struct.pack('<III', UDP_MAGIC, invoke_id, service | UDP_REPLY)
+    msg += netid + struct.pack('<HI', 10000, len(tags))
+    for tag, data in tags:
+        msg += struct.pack('<HH', tag, len(data)) + data
+    return msg
+
+
+def serve_cx_udp():
+    s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    s.bind((args.bind, BECKHOFF_UDP_PORT))
+    while True:
+        (msg, addr) = s.recvfrom(3072)
+        if len(msg) < 12:
+            continue
+        magic, invoke_id, service = struct.unpack('<III', msg[:12])
+        if magic != UDP_MAGIC:
+            continue
+        if service == UDP_IDENTIFY:
+            print(f'identify from {addr}')
+            reply = udp_reply(service, invoke_id, [
+                (TAG_COMPUTERNAME, args.name.encode() + b'\0'),
+                (TAG_TCVERSION, struct.pack('<BBH', major, minor, build)),
+            ])
+        elif service == UDP_ADDROUTE:
+            print(f'add route from {addr}')
+            reply = udp_reply(service, invoke_id, [(TAG_STATUS, struct.pack('<I', 0))])
+        else:
+            continue
+        s.sendto(reply, addr)
+
+
+def serve_bc_udp():
+    s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    s.bind((args.bind, BECKHOFF_UDP_PORT2))
+    while True:
+        (msg, addr) = s.recvfrom(3072)
+        if len(msg) != 16 or struct.unpack('<I', msg[:4])[0] != BC_SCAN_REQUEST:
+            continue
+        print(f'BC scan from {addr}')
+        # each area is echoed, followed by its data
+        name = args.name.encode()[:10].ljust(20, b'\0')
+        reply = struct.pack('<I', BC_SCAN_REPLY) + msg[4:10] + netid + msg[10:16] + name
+        s.sendto(reply, addr)
+
+
+def handle_request(cmd, data):
+    if cmd == GETSTATE:
+        return struct.pack('<IHH', 0, args.state, 0)
+    if cmd == DEVINFO:
+        return struct.pack('<IBBH', 0, major, minor, build) + \
+            args.name.encode()[:15].ljust(16, b'\0')
+    if cmd == READ:
+        ig, io, length = struct.unpack('<III', data[:12])
+        value = read_data.get((ig, io), b'')[:length].ljust(length, b'\0')
+        return struct.pack('<II', 0, len(value)) + value
+    if cmd == WRITE:
+        return struct.pack('<I', 0)
+    return struct.pack('<I', ADSERR_SRVNOTSUPP)
+
+
+def serve_client(sock, addr):
+    while True:
+        data = b''
+        while len(data) < 6:
+            newdata = sock.recv(6 - len(data))
+            if not newdata:
+                print(f'connection from {addr} closed')
+                return
+            data += newdata
+        _zero, size = struct.unpack('<HI', data)
+        while len(data) < size + 6:
+            data += sock.recv(size + 6 - len(data))
+        dest, source = data[6:14], data[14:22]
+        cmd, flags, _len, _err, invoke_id = struct.unpack('<HHIII', data[22:38])
+        if flags & 1:
+            continue
+        print(f'request cmd={cmd} invoke_id={invoke_id:#x} from {addr}')
+        payload = handle_request(cmd, data[38:])
+        header = source + dest + struct.pack('<HHIII', cmd, flags | 1, len(payload),
+                                               0, invoke_id)
+        sock.sendall(struct.pack('<HI', 0, len(header) + len(payload)) + header + payload)
+
+
+threading.Thread(target=serve_bc_udp if args.bc else serve_cx_udp, daemon=True).start()
+
+s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+s.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+s.bind((args.bind, BECKHOFF_TCP_PORT))
+s.listen(5)
+
+while True:
+    print('accepting...')
+    (sock, addr) = s.accept()
+    threading.Thread(target=serve_client, args=(sock, addr), daemon=True).start()
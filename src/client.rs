@@ -0,0 +1,365 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+use std::collections::{HashMap, VecDeque};
+use std::{fmt, io};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use ads::AmsAddr;
+use byteorder::{ByteOrder, LittleEndian as LE, WriteBytesExt};
+use log::{debug, warn};
+
+use crate::forwarder::Beckhoff;
+use crate::util::{AdsError, AdsMessage, AdsMessageBuilder, DEFAULT_MAX_MESSAGE_LEN, DEVINFO,
+                  ADDNOTIF, DELNOTIF, GETSTATE, NOTIF, READ, READWRITE, WRITE,
+                  ads_error_str};
+
+
+/// Errors from ADS requests made by the forwarder itself.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Sending or receiving failed, including timeouts.
+    Io(io::Error),
+    /// The reply could not be parsed.
+    Invalid(AdsError),
+    /// The device replied with this ADS error code.
+    Ads(u32),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "socket error: {err}"),
+            ClientError::Invalid(err) => write!(f, "invalid reply: {err}"),
+            ClientError::Ads(code) => write!(f, "ADS error {code:#x}: {}", ads_error_str(*code)),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(err) => Some(err),
+            ClientError::Invalid(err) => Some(err),
+            ClientError::Ads(_) => None,
+        }
+    }
+}
+
+impl ClientError {
+    /// The ADS error code, if the device replied with an error.
+    pub fn ads_code(&self) -> Option<u32> {
+        match self {
+            ClientError::Ads(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl From<AdsError> for ClientError {
+    fn from(err: AdsError) -> Self {
+        ClientError::Invalid(err)
+    }
+}
+
+
+/// A TCP connection for sending our own ADS requests and waiting for the
+/// replies, e.g. for health checks.
+pub struct AdsConn {
+    sock: TcpStream,
+    source: AmsAddr,
+    invoke_id: u32,
+    /// Symbol handles obtained by `read_by_name`, released on drop
+    handles: HashMap<(AmsAddr, String), u32>,
+    /// Notifications received while waiting for a reply
+    notifications: VecDeque<AdsMessage>,
+}
+
+impl AdsConn {
+    /// Connect to the AMS router at `addr`, sending requests from `source`.
+    ///
+    /// Waiting for a reply fails after `timeout`.
+    pub fn connect(addr: SocketAddr, source: AmsAddr, timeout: Duration) -> io::Result<AdsConn> {
+        let sock = TcpStream::connect_timeout(&addr, timeout)?;
+        sock.set_nodelay(true)?;
+        sock.set_read_timeout(Some(timeout))?;
+        Ok(AdsConn { sock, source, invoke_id: 0, handles: HashMap::new(),
+                     notifications: VecDeque::new() })
+    }
+
+    /// Send a request and wait for the reply with the same invoke ID.
+    ///
+    /// Notifications arriving in the meantime are kept for `recv_notification`,
+    /// other messages are skipped.  An error result in the reply is returned
+    /// as `Err`.
+    pub fn request(&mut self, target: &AmsAddr, cmd: u16, data: &[u8])
+                   -> Result<AdsMessage, ClientError> {
+        self.invoke_id = self.invoke_id.wrapping_add(1);
+        let request = AdsMessageBuilder::new(cmd)
+            .dest(*target)
+            .source(self.source)
+            .invoke_id(self.invoke_id)
+            .data(data)
+            .build();
+        self.sock.write_all(&request.0)?;
+        loop {
+            let reply = self.read_message()?;
+            if reply.is_reply() && reply.get_cmd() == cmd
+                && reply.get_invoke_id() == self.invoke_id
+            {
+                return match reply.get_result() {
+                    Some(0) | None => Ok(reply),
+                    Some(err) => Err(ClientError::Ads(err)),
+                };
+            }
+            if reply.get_cmd() == NOTIF {
+                self.notifications.push_back(reply);
+            } else {
+                debug!("ignoring unexpected message: {reply:?}");
+            }
+        }
+    }
+
+    /// Wait for the next notification from any of the registered handles.
+    ///
+    /// The samples can be extracted with `ads::notif::Notification::new`.
+    pub fn recv_notification(&mut self) -> Result<AdsMessage, ClientError> {
+        loop {
+            let msg = match self.notifications.pop_front() {
+                Some(msg) => msg,
+                None => self.read_message()?,
+            };
+            if msg.get_cmd() == NOTIF {
+                return Ok(msg);
+            }
+            debug!("ignoring unexpected message: {msg:?}");
+        }
+    }
+
+    fn read_message(&mut self) -> Result<AdsMessage, ClientError> {
+        let mut message = vec![0; 6];
+        self.sock.read_exact(&mut message)?;
+        let size = AdsMessage::body_len(&message, DEFAULT_MAX_MESSAGE_LEN)?;
+        message.resize(size + 6, 0);
+        self.sock.read_exact(&mut message[6..])?;
+        Ok(AdsMessage::try_new(message)?)
+    }
+}
+
+
+impl Drop for AdsConn {
+    fn drop(&mut self) {
+        for ((target, name), handle) in std::mem::take(&mut self.handles) {
+            let result = write(self, &target, INDEXGROUP_RELEASE_HANDLE, 0,
+                               &handle.to_le_bytes());
+            if let Err(err) = result {
+                warn!("could not release handle for {name}: {err}");
+            }
+        }
+    }
+}
+
+
+/// Index groups for accessing PLC variables by name.
+const INDEXGROUP_GET_HANDLE: u32 = 0xF003;
+const INDEXGROUP_VALUE_BY_HANDLE: u32 = 0xF005;
+const INDEXGROUP_RELEASE_HANDLE: u32 = 0xF006;
+/// Index group for reading multiple items in a single ReadWrite request.
+const INDEXGROUP_SUM_READ: u32 = 0xF080;
+
+
+/// Check that a reply carries at least `len` bytes after the result field
+/// and return them.
+fn reply_data(reply: &AdsMessage, len: usize) -> Result<&[u8], ClientError> {
+    let data = reply.get_data();
+    if data.len() < 4 + len {
+        return Err(AdsError::TooShort { have: reply.0.len(),
+                                        need: reply.0.len() - data.len() + 4 + len }.into());
+    }
+    Ok(&data[4..])
+}
+
+
+/// Read the ADS state and device state of the target.
+pub fn read_state(conn: &mut AdsConn, target: &AmsAddr) -> Result<(u16, u16), ClientError> {
+    let reply = conn.request(target, GETSTATE, &[])?;
+    let data = reply_data(&reply, 4)?;
+    Ok((LE::read_u16(&data[0..2]), LE::read_u16(&data[2..4])))
+}
+
+/// Read the device name and TwinCAT version (major, minor, build) of the target.
+pub fn read_device_info(conn: &mut AdsConn, target: &AmsAddr)
+                        -> Result<(String, (u8, u8, u16)), ClientError> {
+    let reply = conn.request(target, DEVINFO, &[])?;
+    let data = reply_data(&reply, 20)?;
+    let version = (data[0], data[1], LE::read_u16(&data[2..4]));
+    let name = &data[4..20];
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    Ok((String::from_utf8_lossy(name).into_owned(), version))
+}
+
+/// Fill in the name and version of a Beckhoff from its system service,
+/// e.g. for BCs or devices that were not found by broadcast.
+pub fn update_device_info(conn: &mut AdsConn, bh: &mut Beckhoff) -> Result<(), ClientError> {
+    let (name, version) = read_device_info(conn, &AmsAddr::new(bh.netid, 10000))?;
+    bh.name = name;
+    bh.version = Some(version);
+    Ok(())
+}
+
+/// Read `len` bytes from the given index group and offset.
+pub fn read(conn: &mut AdsConn, target: &AmsAddr, index_group: u32, index_offset: u32,
+            len: usize) -> Result<Vec<u8>, ClientError> {
+    let mut data = Vec::new();
+    data.write_u32::<LE>(index_group).unwrap();
+    data.write_u32::<LE>(index_offset).unwrap();
+    data.write_u32::<LE>(len as u32).unwrap();
+    let reply = conn.request(target, READ, &data)?;
+    read_reply_data(&reply)
+}
+
+/// Write `data` to the given index group and offset.
+///
+/// If the device rejects the write, its error code is returned as
+/// `ClientError::Ads`.
+pub fn write(conn: &mut AdsConn, target: &AmsAddr, index_group: u32, index_offset: u32,
+             data: &[u8]) -> Result<(), ClientError> {
+    let mut req = Vec::with_capacity(12 + data.len());
+    req.write_u32::<LE>(index_group).unwrap();
+    req.write_u32::<LE>(index_offset).unwrap();
+    req.write_u32::<LE>(data.len() as u32).unwrap();
+    req.extend_from_slice(data);
+    conn.request(target, WRITE, &req)?;
+    Ok(())
+}
+
+/// Write `data` to the given index group and offset, and read back up to
+/// `read_len` bytes.
+pub fn read_write(conn: &mut AdsConn, target: &AmsAddr, index_group: u32, index_offset: u32,
+                  read_len: usize, data: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let mut req = Vec::with_capacity(16 + data.len());
+    req.write_u32::<LE>(index_group).unwrap();
+    req.write_u32::<LE>(index_offset).unwrap();
+    req.write_u32::<LE>(read_len as u32).unwrap();
+    req.write_u32::<LE>(data.len() as u32).unwrap();
+    req.extend_from_slice(data);
+    let reply = conn.request(target, READWRITE, &req)?;
+    read_reply_data(&reply)
+}
+
+/// Return the data of a Read or ReadWrite reply, after the length field.
+fn read_reply_data(reply: &AdsMessage) -> Result<Vec<u8>, ClientError> {
+    let len = LE::read_u32(reply_data(reply, 4)?) as usize;
+    Ok(reply_data(reply, 4 + len)?[4..4 + len].to_vec())
+}
+
+/// Read `len` bytes of the PLC variable with the given symbol name.
+///
+/// The symbol handle is looked up once per connection and then cached.
+pub fn read_by_name(conn: &mut AdsConn, target: &AmsAddr, name: &str,
+                    len: usize) -> Result<Vec<u8>, ClientError> {
+    let key = (*target, name.to_string());
+    let handle = match conn.handles.get(&key) {
+        Some(&handle) => handle,
+        None => {
+            let reply = read_write(conn, target, INDEXGROUP_GET_HANDLE, 0, 4, name.as_bytes())?;
+            if reply.len() < 4 {
+                return Err(AdsError::TooShort { have: reply.len(), need: 4 }.into());
+            }
+            let handle = LE::read_u32(&reply);
+            conn.handles.insert(key.clone(), handle);
+            handle
+        }
+    };
+    let result = read(conn, target, INDEXGROUP_VALUE_BY_HANDLE, handle, len);
+    if let Err(ClientError::Ads(_)) = result {
+        // the handle may be stale after an online change, look it up again next time
+        conn.handles.remove(&key);
+    }
+    result
+}
+
+/// Read multiple `(index_group, index_offset, len)` items with a single
+/// sum-command request.
+///
+/// Returns one result per item, either its data or its ADS error code.
+pub fn sum_read(conn: &mut AdsConn, target: &AmsAddr, items: &[(u32, u32, usize)])
+                -> Result<Vec<Result<Vec<u8>, u32>>, ClientError> {
+    let mut req = Vec::with_capacity(12 * items.len());
+    for &(index_group, index_offset, len) in items {
+        req.write_u32::<LE>(index_group).unwrap();
+        req.write_u32::<LE>(index_offset).unwrap();
+        req.write_u32::<LE>(len as u32).unwrap();
+    }
+    // the reply has all error codes first, then the data of each item
+    let data_len: usize = items.iter().map(|item| item.2).sum();
+    let read_len = 4 * items.len() + data_len;
+    let reply = read_write(conn, target, INDEXGROUP_SUM_READ, items.len() as u32,
+                           read_len, &req)?;
+    if reply.len() < read_len {
+        return Err(AdsError::TooShort { have: reply.len(), need: read_len }.into());
+    }
+    let (codes, mut data) = reply.split_at(4 * items.len());
+    Ok(items.iter().enumerate().map(|(i, item)| {
+        let (value, rest) = data.split_at(item.2);
+        data = rest;
+        match LE::read_u32(&codes[4 * i..]) {
+            0 => Ok(value.to_vec()),
+            err => Err(err),
+        }
+    }).collect())
+}
+
+/// Register for notifications of `len` bytes at the given index group and
+/// offset, and return the notification handle.
+///
+/// `trans_mode` is the ADS transmission mode (e.g. 3 for cyclic, 4 for on
+/// change); `max_delay` and `cycle_time` are in units of 100 ns.
+pub fn add_notification(conn: &mut AdsConn, target: &AmsAddr, index_group: u32,
+                        index_offset: u32, len: usize, trans_mode: u32, max_delay: u32,
+                        cycle_time: u32) -> Result<u32, ClientError> {
+    let mut req = Vec::with_capacity(40);
+    req.write_u32::<LE>(index_group).unwrap();
+    req.write_u32::<LE>(index_offset).unwrap();
+    req.write_u32::<LE>(len as u32).unwrap();
+    req.write_u32::<LE>(trans_mode).unwrap();
+    req.write_u32::<LE>(max_delay).unwrap();
+    req.write_u32::<LE>(cycle_time).unwrap();
+    req.extend_from_slice(&[0; 16]); // reserved
+    let reply = conn.request(target, ADDNOTIF, &req)?;
+    Ok(LE::read_u32(reply_data(&reply, 4)?))
+}
+
+/// Delete a notification registered with `add_notification`.
+pub fn delete_notification(conn: &mut AdsConn, target: &AmsAddr,
+                           handle: u32) -> Result<(), ClientError> {
+    conn.request(target, DELNOTIF, &handle.to_le_bytes())?;
+    Ok(())
+}
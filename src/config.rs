@@ -0,0 +1,173 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+use ads::AmsNetId;
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Options;
+use crate::forwarder::Beckhoff;
+
+
+/// Settings read from a TOML file given with `--config`.
+///
+/// See the README for the schema; everything is optional.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen for ADS TCP connections on
+    pub listen: Option<SocketAddr>,
+    /// NetID of the forwarder itself
+    #[serde(with = "opt_netid")]
+    pub netid: Option<AmsNetId>,
+    /// "info" or "debug"
+    pub log_level: Option<LogLevel>,
+    /// Address to serve Prometheus metrics on
+    pub metrics_addr: Option<SocketAddr>,
+    /// Known Beckhoffs, scanned directly instead of broadcasting
+    #[serde(rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Debug,
+}
+
+/// A Beckhoff that the forwarder should route to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    #[serde(with = "netid")]
+    pub netid: AmsNetId,
+    pub ip: Ipv4Addr,
+    /// NetID that clients use for this Beckhoff, if different
+    #[serde(default, with = "opt_netid")]
+    pub client: Option<AmsNetId>,
+}
+
+/// NetIDs are written in their dotted form, e.g. "5.1.2.3.1.1".
+mod netid {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &AmsNetId, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<AmsNetId, D::Error> {
+        String::deserialize(d)?.parse().map_err(de::Error::custom)
+    }
+}
+
+mod opt_netid {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &Option<AmsNetId>, s: S) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => s.collect_str(id),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<AmsNetId>, D::Error> {
+        netid::deserialize(d).map(Some)
+    }
+}
+
+impl Config {
+    /// Read and parse the given config file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path).context("reading config file")?;
+        toml::from_str(&text).context("parsing config file")
+    }
+
+    /// Fill in options not given on the command line from the config.
+    pub fn apply(&self, opts: &mut Options) {
+        opts.listen = opts.listen.or(self.listen);
+        opts.local_ams_net_id = opts.local_ams_net_id.or(self.netid);
+        opts.verbose |= self.log_level == Some(LogLevel::Debug);
+        #[cfg(feature = "metrics")]
+        {
+            opts.metrics_addr = opts.metrics_addr.or(self.metrics_addr);
+        }
+        for route in &self.routes {
+            if let Some(client_id) = route.client {
+                opts.netid_alias.push((client_id, route.netid));
+            }
+        }
+        if opts.target.is_none() && !self.routes.is_empty() {
+            let ips: Vec<_> = self.routes.iter().map(|r| r.ip.to_string()).collect();
+            opts.target = Some(ips.join(","));
+        }
+    }
+
+    /// Warn about configured routes that don't match what the scan found.
+    pub fn check_routes(&self, found: &[Beckhoff]) {
+        for route in &self.routes {
+            match found.iter().find(|bh| bh.bh_addr == route.ip) {
+                Some(bh) if bh.netid != route.netid =>
+                    warn!("config: {} has NetID {}, not {}", route.ip, bh.netid, route.netid),
+                Some(_) => {}
+                None => warn!("config: no Beckhoff found at {}", route.ip),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trip() {
+        let config = Config {
+            listen: Some("127.0.0.1:48898".parse().unwrap()),
+            netid: Some(AmsNetId::new(10, 0, 0, 1, 1, 1)),
+            log_level: Some(LogLevel::Debug),
+            metrics_addr: Some("0.0.0.0:9100".parse().unwrap()),
+            routes: vec![
+                Route { netid: AmsNetId::new(5, 1, 2, 3, 1, 1), ip: Ipv4Addr::new(10, 0, 0, 5),
+                        client: None },
+                Route { netid: AmsNetId::new(5, 1, 2, 4, 1, 1), ip: Ipv4Addr::new(10, 0, 0, 6),
+                        client: Some(AmsNetId::new(5, 9, 9, 9, 1, 1)) },
+            ],
+        };
+        let text = toml::to_string(&config).unwrap();
+        assert!(text.contains("netid = \"10.0.0.1.1.1\""));
+        assert!(text.contains("client = \"5.9.9.9.1.1\""));
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+    }
+
+    #[test]
+    fn empty_config_round_trip() {
+        let text = toml::to_string(&Config::default()).unwrap();
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), Config::default());
+    }
+}
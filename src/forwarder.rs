@@ -20,12 +20,15 @@
 //
 // *****************************************************************************
 
+use std::cell::Cell;
+use std::cmp;
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket, SocketAddr, Ipv4Addr};
-use std::sync::Arc;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket, SocketAddr, IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread;
 
 use ads::{AmsAddr, AmsNetId, udp};
@@ -33,12 +36,14 @@ use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, info, warn, error};
 use byteorder::{ByteOrder, LittleEndian as LE, WriteBytesExt};
 use crossbeam_channel::{self, Receiver, Sender, Select};
-use mlzutil::{spawn, bytes::hexdump};
+use mlzutil::spawn;
 
 use crate::Options;
-use crate::util::{AdsMessage, InOutClientBH, BECKHOFF_UDP_PORT, BECKHOFF_BC_UDP_PORT,
-                  BECKHOFF_TCP_PORT, DUMMY_NETID, FWDER_NETID, GETSTATE, ADDNOTIF,
-                  DELNOTIF, NOTIF, WRITE, DEVINFO, NotifData};
+use crate::stats::{Direction, Stats};
+use crate::util::{AdsCommand, AdsMessage, CommandFilter, InOutClientBH, NetIdMap,
+                  BECKHOFF_UDP_PORT, BECKHOFF_BC_UDP_PORT, BECKHOFF_TCP_PORT, DUMMY_NETID, FWDER_NETID,
+                  GETSTATE, ADDNOTIF, DELNOTIF, NOTIF, WRITE, DEVINFO, NotifData,
+                  HEADER_LEN, ads_error_str, hexdump_colored, hexdump_colored_at};
 
 
 #[derive(Clone, PartialEq, Eq)]
@@ -54,11 +59,103 @@ pub struct Beckhoff {
     pub bh_addr: Ipv4Addr,
     pub netid: AmsNetId,
     pub typ: BhType,
+    pub name: String,
+    /// TwinCAT version (major, minor, build), not known for BCs
+    pub version: Option<(u8, u8, u16)>,
+    /// Time between sending the scan packet and receiving the reply
+    pub rtt: Option<Duration>,
+}
+
+// Beckhoffs are identified by NetID and address, and sorted in this order.
+impl PartialEq for Beckhoff {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Beckhoff {}
+
+impl PartialOrd for Beckhoff {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Beckhoff {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.netid.0, self.bh_addr).cmp(&(other.netid.0, other.bh_addr))
+    }
+}
+
+impl fmt::Display for Beckhoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}) at {} [{}]",
+               if self.name.is_empty() { "<???>" } else { &self.name },
+               self.netid, self.bh_addr, if self.typ == BhType::BC { "BC" } else { "CX" })
+    }
 }
 
 impl Beckhoff {
+    /// Return a JSON object describing this Beckhoff.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut obj = serde_json::json!({
+            "netid": self.netid.to_string(),
+            "ip": self.bh_addr.to_string(),
+            "if_addr": self.if_addr.to_string(),
+            "is_bc": self.typ == BhType::BC,
+        });
+        if !self.name.is_empty() {
+            obj["name"] = self.name.clone().into();
+        }
+        if let Some((major, minor, build)) = self.version {
+            obj["twincat_version"] = format!("{major}.{minor}.{build}").into();
+        }
+        if let Some(rtt) = self.rtt {
+            obj["rtt_ms"] = (rtt.as_secs_f64() * 1000.).into();
+        }
+        obj
+    }
+
+    /// Recreate a Beckhoff from the output of `to_json`.
+    pub fn from_json(obj: &serde_json::Value) -> Option<Beckhoff> {
+        let field = |name: &str| obj.get(name).and_then(|v| v.as_str());
+        let version = match field("twincat_version") {
+            Some(ver) => {
+                let mut parts = ver.split('.');
+                Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?,
+                      parts.next()?.parse().ok()?))
+            }
+            None => None,
+        };
+        let typ = if obj.get("is_bc")?.as_bool()? {
+            BhType::BC
+        } else if version.map_or(false, |ver: (u8, u8, u16)| ver.0 == 2) {
+            BhType::CX2
+        } else {
+            BhType::CX3
+        };
+        Some(Beckhoff {
+            if_addr: field("if_addr")?.parse().ok()?,
+            bh_addr: field("ip")?.parse().ok()?,
+            netid: field("netid")?.parse().ok()?,
+            typ,
+            name: field("name").unwrap_or_default().into(),
+            version,
+            rtt: obj.get("rtt_ms").and_then(|v| v.as_f64())
+                    .map(|ms| Duration::from_secs_f64(ms / 1000.)),
+        })
+    }
+
     /// Add a route on the Beckhoff, to `netid` via our interface address.
     fn add_route(&self, netid: AmsNetId, name: &str) -> Result<()> {
+        // try the standard passwords
+        self.add_route_as(netid, name, "Administrator", &["", "1"])
+    }
+
+    /// Add a route on the Beckhoff using the given credentials, trying each
+    /// password in turn until one is accepted.
+    pub fn add_route_as(&self, netid: AmsNetId, name: &str, user: &str,
+                        passwords: &[&str]) -> Result<()> {
         if self.typ == BhType::BC {
             // no routes necessary on BCs
             return Ok(());
@@ -66,11 +163,11 @@ impl Beckhoff {
         let sock = UdpSocket::bind(("0.0.0.0", 0)).context("binding UDP socket")?;
         sock.set_read_timeout(Some(Duration::from_millis(1500)))?;
 
-        for password in &["", "1"] {
+        for password in passwords {
             let mut msg = udp::Message::new(udp::ServiceId::AddRoute, AmsAddr::new(netid, 10000));
             msg.add_str(udp::Tag::RouteName, name);
             msg.add_bytes(udp::Tag::NetID, &netid.0);
-            msg.add_str(udp::Tag::UserName, "Administrator");
+            msg.add_str(udp::Tag::UserName, user);
             msg.add_str(udp::Tag::Password, password);
             msg.add_str(udp::Tag::ComputerName, &format!("{}", self.if_addr));
             sock.send_to(msg.as_bytes(), (self.bh_addr, BECKHOFF_UDP_PORT))?;
@@ -83,11 +180,12 @@ impl Beckhoff {
                 Some(0) => { info!("added route if_addr={} netid={netid}", self.bh_addr);
                              return Ok(())},
                 Some(0x0704) => continue,  // password not accepted
-                Some(err) => bail!("error return when adding route: {err:#x}"),
+                Some(err) => bail!("error return when adding route: {err:#x} ({})",
+                                   ads_error_str(err)),
                 None => bail!("invalid return message adding route"),
             }
         }
-        bail!("standard Administrator passwords not accepted");
+        bail!("passwords for {user} not accepted");
     }
 
     /// Remove all routes on the Beckhoff with given name.
@@ -104,7 +202,7 @@ impl Beckhoff {
         data.write_all(&[0]).unwrap();
         let invoke_id = 0;
 
-        let msg = AdsMessage::new(self.netid, 10000, *netid, 40001,
+        let msg = AdsMessage::new(AmsAddr::new(self.netid, 10000), AmsAddr::new(*netid, 40001),
                                   WRITE, false, invoke_id, &data);
         sock.write_all(&msg.0).context("removing routes")?;
 
@@ -118,12 +216,15 @@ impl Beckhoff {
 pub struct Forwarder {
     opts: Options,
     bh: Beckhoff,
+    stats: Arc<Mutex<Stats>>,
 }
 
 struct ClientRequest {
     index: usize,
     invoke_id: u32,
     add_notif_req_data: Option::<crate::util::AddNotifReqData>,
+    /// When the request was sent to the Beckhoff, for round-trip timing
+    sent: Instant,
 }
 
 /// The distributor is the heart of the TCP part of the forwarder.
@@ -133,10 +234,39 @@ struct Distributor {
     bh: Beckhoff,
     local_ams_net_id: AmsNetId,
     ids: Vec<u8>,
+    /// Virtual NetIDs of closed clients, with the number of replies to the
+    /// DELNOTIFs sent for them still to come; the ID is only reused after
+    /// that, so that a new client doesn't get the replies
+    pending_cleanup: HashMap<AmsNetId, usize>,
     dump: bool,
     summarize: bool,
+    /// Log a one-line summary of each forwarded message
+    log_messages: bool,
     single_ams_net_id: bool,
     sig: Arc<AtomicBool>,
+    /// Set by SIGUSR1 to request logging the statistics
+    dump_stats: Arc<AtomicBool>,
+    stats: Arc<Mutex<Stats>>,
+    /// Which client requests may be forwarded
+    command_filter: CommandFilter,
+    /// NetIDs that clients use for the Beckhoff, and the real one
+    aliases: NetIdMap,
+    /// TCP keepalive time and interval for the sockets
+    keepalive: Option<(Duration, Duration)>,
+    /// Close client connections idle for longer than this
+    idle_timeout: Option<Duration>,
+    /// Window for coalescing notifications, and clients not to apply it to
+    coalesce_window: Option<Duration>,
+    coalesce_exempt: Vec<IpAddr>,
+    /// Largest message accepted from any socket
+    max_message_len: usize,
+    /// Limits for frames and bytes per second from each client
+    rate_limit_frames: Option<u32>,
+    rate_limit_bytes: Option<u32>,
+    /// How long to wait for in-flight requests when shutting down
+    grace: Duration,
+    /// Set once a shutdown signal has been received
+    shutdown_deadline: Option<Instant>,
     clients: Vec<ClientConn>,
     invoke_id_client_req: u32,
     invoke_id_our_req: u32,
@@ -165,11 +295,65 @@ struct ClientConn {
     sock: TcpStream, // socket to write messages to
     chan: Receiver<ReadEvent>, // channel to receive messages (or quit)
     peer: SocketAddr, // peer address for convenience
-    client_id: AmsNetId, // client's real ID
-    client_source_port: u16,
-    clients_bh_id: AmsNetId, // client thinks this is Beckhoff's ID
-    clients_bh_dest_port: u16, // client talks to this port
+    client_addr: AmsAddr, // client's real address
+    clients_bh_addr: AmsAddr, // client thinks this is Beckhoff's address
     virtual_id: AmsNetId, // virtual ID for the temporary route
+    last_traffic: Cell<Instant>, // last frame in either direction
+    notif_handles: Vec<(u16, u32)>, // AMS port and handle of notifications
+    coalescer: Option<Coalescer>, // rate limit for notifications, if enabled
+    in_flight: usize, // requests without a reply yet (if not using a single NetID)
+}
+
+/// Result of offering a notification to a `Coalescer`.
+enum Offer {
+    /// Forward the notification now.
+    Send(AdsMessage),
+    /// Kept back until the end of the window.
+    Held,
+    /// Kept back, and an older notification kept back before was dropped.
+    Replaced,
+}
+
+/// Forwards at most one notification per handle and time window, keeping
+/// only the latest one of each window.
+struct Coalescer {
+    window: Duration,
+    /// Last time a notification was sent, and the pending one if any
+    handles: HashMap<u32, (Instant, Option<AdsMessage>)>,
+}
+
+impl Coalescer {
+    fn new(window: Duration) -> Self {
+        Coalescer { window, handles: HashMap::new() }
+    }
+
+    fn offer(&mut self, handle: u32, frame: AdsMessage) -> Offer {
+        let now = Instant::now();
+        match self.handles.get_mut(&handle) {
+            Some((sent, pending)) if now.duration_since(*sent) < self.window => {
+                if pending.replace(frame).is_some() { Offer::Replaced } else { Offer::Held }
+            }
+            _ => {
+                self.handles.insert(handle, (now, None));
+                Offer::Send(frame)
+            }
+        }
+    }
+
+    /// Return the pending notifications whose window has passed.
+    fn due(&mut self) -> Vec<AdsMessage> {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        for (sent, pending) in self.handles.values_mut() {
+            if now.duration_since(*sent) >= self.window {
+                if let Some(frame) = pending.take() {
+                    *sent = now;
+                    frames.push(frame);
+                }
+            }
+        }
+        frames
+    }
 }
 
 enum ReadEvent {
@@ -177,7 +361,85 @@ enum ReadEvent {
     Quit,
 }
 
-fn read_loop(mut sock: TcpStream, chan: Sender<ReadEvent>) {
+/// Token bucket allowing `rate` units per second, with bursts of up to one
+/// second's worth.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        TokenBucket { rate: rate as f64, tokens: rate as f64, last: Instant::now() }
+    }
+
+    /// Take `n` tokens, returning how long to wait until they are covered.
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - n as f64;
+        self.last = now;
+        if self.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Per-connection limit on the frames and bytes a client may send.
+///
+/// Frames above the limit are not rejected; reading from the client is
+/// paused instead, so that TCP back-pressures it.
+struct RateLimiter {
+    frames: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl RateLimiter {
+    fn new(frames: Option<u32>, bytes: Option<u32>, stats: Arc<Mutex<Stats>>) -> Option<Self> {
+        if frames.is_none() && bytes.is_none() {
+            return None;
+        }
+        Some(RateLimiter { frames: frames.map(TokenBucket::new),
+                           bytes: bytes.map(TokenBucket::new), stats })
+    }
+
+    /// Account for a frame of `len` bytes, sleeping if over the limit.
+    fn throttle(&mut self, len: usize) {
+        let wait_frames = self.frames.as_mut().map_or(Duration::ZERO, |b| b.take(1));
+        let wait_bytes = self.bytes.as_mut().map_or(Duration::ZERO, |b| b.take(len));
+        let wait = wait_frames.max(wait_bytes);
+        if !wait.is_zero() {
+            self.stats.lock().unwrap().throttled += 1;
+            debug!("client over rate limit, pausing for {wait:?}");
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Enable TCP keepalive on the socket, so that dead peers are noticed.
+fn set_keepalive(sock: &TcpStream, keepalive: Option<(Duration, Duration)>) -> io::Result<()> {
+    if let Some((time, interval)) = keepalive {
+        let params = socket2::TcpKeepalive::new().with_time(time).with_interval(interval);
+        socket2::SockRef::from(sock).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// Log a one-line summary of a forwarded message; with `dump`, also dump
+/// its payload, addressed by its offset within the frame.
+fn log_message(direction: &str, msg: &AdsMessage, dump: bool) {
+    info!("{direction}: {}", msg.summary_line());
+    if dump && !msg.get_data().is_empty() {
+        hexdump_colored_at(msg.get_data(), HEADER_LEN);
+    }
+}
+
+fn read_loop(mut sock: TcpStream, chan: Sender<ReadEvent>, mut limiter: Option<RateLimiter>,
+             max_len: usize) {
     loop {
         let mut message = Vec::with_capacity(100);
         // read size
@@ -186,20 +448,52 @@ fn read_loop(mut sock: TcpStream, chan: Sender<ReadEvent>) {
             let _ = chan.send(ReadEvent::Quit);
             return;
         }
-        let size = LE::read_u32(&message[2..6]);
+        // don't trust the length before allocating; after a bad frame the
+        // stream can't be resynchronized, so the connection is closed
+        let size = match AdsMessage::body_len(&message, max_len) {
+            Ok(size) => size,
+            Err(err) => {
+                match sock.peer_addr() {
+                    Ok(peer) => warn!("closing connection from {peer}: {err}"),
+                    Err(_) => warn!("closing connection: {err}"),
+                }
+                let _ = sock.shutdown(Shutdown::Both);
+                let _ = chan.send(ReadEvent::Quit);
+                return;
+            }
+        };
         // read rest of message
-        message.resize(size as usize + 6, 0);
+        message.resize(size + 6, 0);
         if sock.read_exact(&mut message[6..]).is_err() {
             let _ = chan.send(ReadEvent::Quit);
             return;
         }
-        // send message to distributor
-        if chan.send(ReadEvent::Msg(AdsMessage::from_bytes(message))).is_err() {
-            return;
+        if let Some(limiter) = &mut limiter {
+            limiter.throttle(message.len());
+        }
+        // send message to distributor, dropping invalid ones
+        match AdsMessage::try_new(message) {
+            Ok(msg) => if chan.send(ReadEvent::Msg(msg)).is_err() {
+                return;
+            },
+            Err(err) => warn!("dropping invalid message: {err}"),
         }
     }
 }
 
+/// ADS error returned for requests rejected by the command filter
+/// ("reading/writing not permitted").
+const ADSERR_NOT_PERMITTED: u32 = 0x704;
+
+/// Delays between attempts to reconnect to the Beckhoff.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(16);
+/// A connection must last this long before the delay starts over, so that
+/// a Beckhoff accepting and then dropping connections is not hammered.
+const RECONNECT_STABLE_TIME: Duration = Duration::from_secs(30);
+/// How often the reconnect delay checks for a shutdown request.
+const RECONNECT_SLEEP_SLICE: Duration = Duration::from_millis(100);
+
 enum DistEvent {
     None,
     BeckhoffMessage(AdsMessage),
@@ -217,6 +511,7 @@ impl Distributor {
         let bh_sock = TcpStream::connect((self.bh.bh_addr, BECKHOFF_TCP_PORT))
             .context("connecting to Beckhoff")?;
         bh_sock.set_nodelay(true)?;
+        set_keepalive(&bh_sock, self.keepalive)?;
         info!("connected to Beckhoff at {}", bh_sock.peer_addr()?);
         let (bh_tx, bh_rx) = crossbeam_channel::unbounded();
 
@@ -229,7 +524,8 @@ impl Distributor {
         // send BH replies from socket to distributor
         let bh_sock2 = bh_sock.try_clone()?;
         let bh_tx2 = bh_tx.clone();
-        spawn("BH reader", move || read_loop(bh_sock2, bh_tx2));
+        let max_len = self.max_message_len;
+        spawn("BH reader", move || read_loop(bh_sock2, bh_tx2, None, max_len));
         self.bh_tx = bh_tx;
         Ok((bh_sock, bh_rx))
     }
@@ -242,7 +538,7 @@ impl Distributor {
     fn run_keepalive(&self, sock: &TcpStream) -> Result<()> {
         let mut bh_sock = sock.try_clone()?;
         let invoke_id = 0;
-        let msg = AdsMessage::new(self.bh.netid, 10000, DUMMY_NETID, 40001,
+        let msg = AdsMessage::new(AmsAddr::new(self.bh.netid, 10000), AmsAddr::new(DUMMY_NETID, 40001),
                                   DEVINFO, false, invoke_id, &[]);
 
         spawn("keepalive", move || loop {
@@ -259,21 +555,53 @@ impl Distributor {
     /// Main entry point for the distributor.
     ///
     /// Opens a connection and handles messages; if the Beckhoff connection
-    /// is closed, it is tried to reopen every second.
+    /// is closed, it is reopened, waiting longer after each failed attempt
+    /// or connection that was dropped again quickly.
     fn run(mut self) {
         mlzlog::set_thread_prefix("TCP: ");
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut connected_before = false;
         while !self.sig.load(Ordering::Relaxed) {
             self.clients.clear();
             match self.connect() {
-                Ok((bh_sock, bh_chan)) => self.handle_msg(bh_sock, bh_chan), // XX3
+                Ok((bh_sock, bh_chan)) => {
+                    if connected_before {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.reconnects += 1;
+                        info!("reconnected to Beckhoff ({} reconnects so far)", stats.reconnects);
+                    }
+                    connected_before = true;
+                    let connected = Instant::now();
+                    self.handle_msg(bh_sock, bh_chan); // XX3
+                    if connected.elapsed() >= RECONNECT_STABLE_TIME {
+                        delay = RECONNECT_MIN_DELAY;
+                    } else if !self.sig.load(Ordering::Relaxed) {
+                        warn!("connection to Beckhoff lost quickly, reconnecting in {delay:?}");
+                        self.sleep_unless_stopped(delay);
+                        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    }
+                }
                 Err(err) => {
-                    error!("error on connection to Beckhoff: {err:#}");
-                    thread::sleep(Duration::from_secs(1));
+                    error!("error on connection to Beckhoff: {err:#}, retrying in {delay:?}");
+                    self.sleep_unless_stopped(delay);
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
                 }
             }
         }
     }
 
+    /// Sleep for `delay`, but return early if the forwarder is shut down.
+    fn sleep_unless_stopped(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        while !self.sig.load(Ordering::Relaxed) {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(RECONNECT_SLEEP_SLICE));
+        }
+    }
+
     /// Wait for an event from all possible sources.
     fn get_event(&self, bh_chan: &Receiver<ReadEvent>) -> DistEvent {
         let mut select = Select::new();
@@ -290,7 +618,11 @@ impl Distributor {
         }
         let new_conn = select.recv(&self.conn_rx);
         select.recv(bh_chan);
-        let event = select.select_timeout(Duration::from_millis(500));
+        // wake up often enough to send coalesced notifications in time
+        let timeout = self.coalesce_window.map_or(Duration::from_millis(500),
+                                                  |w| w.clamp(Duration::from_millis(1),
+                                                              Duration::from_millis(500)));
+        let event = select.select_timeout(timeout);
         if let Ok(event) = event {
             let index = event.index();
             if index == new_conn {
@@ -327,19 +659,33 @@ impl Distributor {
                         notif_data.add_stamp(sample.timestamp, &[(sample.handle, sample.data)]);
                         for &index in notif_indices {
                             if self.clients.len() > index {
-                                let client = &self.clients[index];
+                                let client = &mut self.clients[index];
                                 if client.used {
-                                    let reply = AdsMessage::new(client.client_id,
-                                                                client.client_source_port,
-                                                                client.clients_bh_id,
-                                                                client.clients_bh_dest_port,
+                                    let reply = AdsMessage::new(client.client_addr,
+                                                                client.clients_bh_addr,
                                                                 NOTIF,
                                                                 is_reply,
                                                                 invoke_id,
                                                                 notif_data.data());
+                                    let reply = match &mut client.coalescer {
+                                        Some(coalescer) => match coalescer.offer(sample.handle, reply) {
+                                            Offer::Send(reply) => reply,
+                                            Offer::Held => { any_client = true; continue; }
+                                            Offer::Replaced => {
+                                                self.stats.lock().unwrap().notifications_dropped += 1;
+                                                any_client = true;
+                                                continue;
+                                            }
+                                        },
+                                        None => reply,
+                                    };
                                     if self.summarize {
                                         reply.summarize(InOutClientBH::OutToClnt, self.dump);
                                     }
+                                    if self.log_messages {
+                                        log_message("Beckhoff -> client", &reply, self.dump);
+                                    }
+                                    client.last_traffic.set(Instant::now());
                                     if let Err(err) = (&client.sock).write_all(&reply.0) {
                                         warn!("error forwarding reply to client: {err}");
                                     }
@@ -374,19 +720,42 @@ impl Distributor {
     /// Handle messages once the connection to the Beckhoff is established.
     fn handle_msg(&mut self, mut bh_sock: TcpStream, bh_chan: Receiver<ReadEvent>) {
         'select: loop {
-            // check for interrupt signal
+            // check for interrupt signal; stop once all requests are answered
             if self.sig.load(Ordering::Relaxed) {
-                info!("exiting, removing routes...");
-                if let Err(err) = self.bh.remove_routes(&mut bh_sock, &self.local_ams_net_id, "forwarder") {
-                    warn!("could not remove forwarder route: {err:#}");
-                }
-                if let Err(err) = self.bh.remove_routes(&mut bh_sock, &self.local_ams_net_id, "fwdclient") {
-                    warn!("could not remove forwarder client routes: {err:#}");
+                let in_flight = self.in_flight();
+                let grace = self.grace;
+                let deadline = *self.shutdown_deadline.get_or_insert_with(|| {
+                    info!("shutting down, waiting up to {grace:?} for {in_flight} in-flight requests");
+                    Instant::now() + grace
+                });
+                if in_flight == 0 || Instant::now() >= deadline {
+                    if in_flight > 0 {
+                        warn!("grace period expired with {in_flight} requests still in flight");
+                    }
+                    self.shutdown(&mut bh_sock);
+                    return;
                 }
-                return;
+            }
+            if let Some(timeout) = self.idle_timeout {
+                self.close_idle_clients(timeout);
+            }
+            self.flush_notifications();
+            if self.dump_stats.swap(false, Ordering::Relaxed) {
+                info!("stats: {}", self.stats.lock().unwrap().snapshot());
             }
             // get an event
-            match self.get_event(&bh_chan) {
+            let event = self.get_event(&bh_chan);
+            self.stats.lock().unwrap().active_connections =
+                self.clients.iter().filter(|c| c.used).count();
+            let draining = self.shutdown_deadline.is_some();
+            match event {
+                // while shutting down, only replies from the Beckhoff are handled
+                DistEvent::NewClient(sock) if draining => {
+                    let _ = sock.shutdown(Shutdown::Both);
+                },
+                DistEvent::ClientMessage(index, msg) if draining => {
+                    debug!("dropping request from client {index} during shutdown: {msg:?}");
+                },
                 DistEvent::NewClient(sock) => if let Err(err) = self.new_tcp_conn(sock) {
                     warn!("error handling new client connection: {err:#}");
                 },
@@ -394,19 +763,26 @@ impl Distributor {
                     self.client_msg(msg, index, &mut bh_sock);
                 },
                 DistEvent::BeckhoffMessage(mut msg) => {
+                    self.stats.lock().unwrap().record(Direction::FromBeckhoff, &msg);
                     if self.summarize {
                         info!("From Beckhoff =========================================");
                         msg.summarize(InOutClientBH::InFrmBeck, self.dump);
                     }
+                    match msg.get_result() {
+                        Some(0) | None => {}
+                        Some(err) => warn!("{}:{} replied to {} (invoke ID {:#x}) with error \
+                                            {err:#x}: {}",
+                                           msg.get_source_id(), msg.get_source_port(),
+                                           msg.get_cmd_name(), msg.get_invoke_id(),
+                                           ads_error_str(err)),
+                    }
 
                     // Reply to GetState query from the Beckhoff's AMS router
                     if msg.get_dest_id() == self.local_ams_net_id && msg.get_cmd() == GETSTATE {
-                        let stf = msg.get_state_flags();
-                        let is_reply = stf & 1 != 0;
-                        if !is_reply {
-                            let reply_msg = AdsMessage::new(self.bh.netid, 10000,
-                                                            self.local_ams_net_id, 10000, 4,
-                                                            true, 0, b"\x00\x05\x00\x00");
+                        if !msg.is_reply() {
+                            let reply_msg = AdsMessage::new(AmsAddr::new(self.bh.netid, 10000),
+                                                            AmsAddr::new(self.local_ams_net_id, 10000),
+                                                            GETSTATE, true, 0, b"\x00\x05\x00\x00");
                             bh_sock.write_all(&reply_msg.0).unwrap();
                             info!("replied to router GetState msg");
                             continue 'select;
@@ -415,12 +791,16 @@ impl Distributor {
 
                     if self.single_ams_net_id {
                         let cmd = msg.get_cmd();
-                        if cmd == DELNOTIF {
-                            // Do something ?
-                            continue 'select;
-                        } else if cmd == NOTIF {
-                            self.handle_notification(msg);
-                            continue 'select;
+                        match AdsCommand::from(cmd) {
+                            AdsCommand::DeleteNotification => {
+                                // Do something ?
+                                continue 'select;
+                            }
+                            AdsCommand::Notification => {
+                                self.handle_notification(msg);
+                                continue 'select;
+                            }
+                            _ => {}
                         }
 
                         // find the matching invoke ID and client for this message
@@ -430,6 +810,8 @@ impl Distributor {
                                 let index = client_req.index;
                                 let invoke_id_orig = client_req.invoke_id;
                                 msg.patch_invoke_id(invoke_id_orig);
+                                debug!("reply for client {index} invoke ID {invoke_id_orig} after {:?}",
+                                       client_req.sent.elapsed());
 
                                 // if it is an add-notification message, remember the notification handles
                                 if let Some(handle) = msg.get_add_notification_reply_handle() {
@@ -472,28 +854,54 @@ impl Distributor {
                                 continue 'select;
                             },
                             None => {
-                                info!("get_event invoke_id_patched={invoke_id_patched} NOT FOUND");
+                                info!("get_event invoke_id_patched={invoke_id_patched} \
+                                       NOT FOUND: {msg:?}");
                             },
                         }
                         continue 'select;
                     }
-                    for client in &self.clients {
-                        if client.used && client.virtual_id == msg.get_dest_id() {
-                            self.msg_from_beckhoff(msg, client);
+                    let dest_id = msg.get_dest_id();
+                    if let Some(index) = self.clients.iter()
+                                             .position(|c| c.used && c.virtual_id == dest_id) {
+                        if msg.is_reply() {
+                            let client = &mut self.clients[index];
+                            client.in_flight = client.in_flight.saturating_sub(1);
+                        }
+                        // remember notification handles, to delete them when the client quits
+                        if let Some(handle) = msg.get_add_notification_reply_handle() {
+                            self.clients[index].notif_handles.push((msg.get_source_port(), handle));
+                        }
+                        if msg.get_cmd() == NOTIF && self.clients[index].coalescer.is_some() {
+                            self.coalesce_notification(index, msg);
                             continue 'select;
                         }
+                        self.msg_from_beckhoff(msg, &self.clients[index]);
+                        continue 'select;
+                    }
+                    if let Some(pending) = self.pending_cleanup.get_mut(&dest_id) {
+                        // reply to the cleanup of a closed client, or a late
+                        // notification for it
+                        if msg.is_reply() && msg.get_cmd() == DELNOTIF {
+                            *pending -= 1;
+                            if *pending == 0 {
+                                self.pending_cleanup.remove(&dest_id);
+                                self.release_id(dest_id);
+                            }
+                        }
+                        continue 'select;
                     }
                     if msg.get_dest_id() == DUMMY_NETID {
                         // keepalive reply
                         continue;
                     }
                     if msg.get_dest_id() != self.local_ams_net_id {
-                        warn!("message from Beckhoff to {} not forwarded", msg.get_dest_id());
+                        warn!("message from Beckhoff not forwarded: {msg:?}");
                         continue 'select;
                     }
                     if msg.get_cmd() == GETSTATE {
-                        let reply_msg = AdsMessage::new(self.bh.netid, 10000, self.local_ams_net_id,
-                                                        10000, 4, true, 0, b"\x00\x05\x00\x00");
+                        let reply_msg = AdsMessage::new(AmsAddr::new(self.bh.netid, 10000),
+                                                        AmsAddr::new(self.local_ams_net_id, 10000),
+                                                        GETSTATE, true, 0, b"\x00\x05\x00\x00");
                         bh_sock.write_all(&reply_msg.0).unwrap();
                         info!("replied to router GetState msg");
                     }
@@ -505,11 +913,26 @@ impl Distributor {
                         info!("connection from {} closed", clientx.peer);
                     } else {
                         let clientx = self.clients.swap_remove(index);
-                        let cid = clientx.virtual_id.0[3];
-                        if cid != 0 {
-                            self.ids.push(cid);
-                        }
                         info!("connection from {} closed", clientx.peer);
+                        // the Beckhoff would keep sending these notifications
+                        let mut pending = 0;
+                        for &(port, handle) in &clientx.notif_handles {
+                            debug!("ClientQuit deleting notification handle={handle}");
+                            let req_msg = AdsMessage::new(
+                                AmsAddr::new(self.bh.netid, port),
+                                AmsAddr::new(clientx.virtual_id, clientx.client_addr.port()),
+                                DELNOTIF, false, 0, &handle.to_le_bytes());
+                            match bh_sock.write_all(&req_msg.0) {
+                                Ok(()) => pending += 1,
+                                Err(err) => warn!("error deleting notification of closed \
+                                                   client: {err}"),
+                            }
+                        }
+                        if pending > 0 {
+                            self.pending_cleanup.insert(clientx.virtual_id, pending);
+                        } else {
+                            self.release_id(clientx.virtual_id);
+                        }
                     }
                     let mut notif_req_data_to_beleted = Vec::new();
                     for notif_req_data in self.notif_req_data_to_handle_map.keys() {
@@ -538,10 +961,9 @@ impl Distributor {
                                         let mut data = Vec::new();
                                         data.write_u32::<LE>(handle).unwrap();
 
-                                        let req_msg = AdsMessage::new(self.bh.netid,
-                                                                      notif_req_data.dest_port,
-                                                                      self.local_ams_net_id,
-                                                                      10000,
+                                        let req_msg = AdsMessage::new(AmsAddr::new(self.bh.netid,
+                                                                                   notif_req_data.dest_port),
+                                                                      AmsAddr::new(self.local_ams_net_id, 10000),
                                                                       DELNOTIF, is_reply, invoke_id,
                                                                       &data);
                                         if self.summarize {
@@ -577,10 +999,16 @@ impl Distributor {
                                 warn!("error shutting down client: {err}");
                             }
                         }
+                        client.in_flight = 0;
                     }
                     self.notif_req_data_to_handle_map.clear();
                     self.notif_handle_to_client_indices_map.clear();
                     self.notif_handle_to_last_notif_stream_map.clear();
+                    // replies to in-flight requests will never arrive
+                    self.invoke_id_to_client_map.clear();
+                    for (id, _) in std::mem::take(&mut self.pending_cleanup) {
+                        self.release_id(id);
+                    }
                     return;
                 },
                 DistEvent::None => continue
@@ -598,38 +1026,172 @@ impl Distributor {
         if peer.ip() == self.bh.bh_addr {
             info!("new back-connection from Beckhoff");
             let bh_tx = self.bh_tx.clone();
-            spawn("BH reader", move || read_loop(sock, bh_tx));
+            let max_len = self.max_message_len;
+            spawn("BH reader", move || read_loop(sock, bh_tx, None, max_len));
             return Ok(())
         }
         info!("new connection from {peer}");
+        let coalescer = match self.coalesce_window {
+            Some(window) if !self.coalesce_exempt.contains(&peer.ip()) => {
+                info!("coalescing notifications to {peer} every {window:?}");
+                Some(Coalescer::new(window))
+            }
+            _ => None,
+        };
         let (cl_tx, cl_rx) = crossbeam_channel::unbounded();
         let sock2 = sock.try_clone()?;
-        spawn("client reader", move || read_loop(sock2, cl_tx));
+        let limiter = RateLimiter::new(self.rate_limit_frames, self.rate_limit_bytes,
+                                       self.stats.clone());
+        let max_len = self.max_message_len;
+        spawn("client reader", move || read_loop(sock2, cl_tx, limiter, max_len));
         let id = self.ids.pop().ok_or_else(|| anyhow!("too many clients"))?;
         let virtual_id = AmsNetId([10, 1, 0, id, 1, 1]);
         if ! self.single_ams_net_id {
             info!("assigned virtual NetID {virtual_id}");
         }
         self.clients.push(ClientConn { used: true, sock, peer, virtual_id, chan: cl_rx,
-                                       client_id: Default::default(),
-                                       client_source_port: 0,
-                                       clients_bh_id: Default::default(),
-                                       clients_bh_dest_port: 0});
+                                       client_addr: AmsAddr::new(Default::default(), 0),
+                                       clients_bh_addr: AmsAddr::new(Default::default(), 0),
+                                       last_traffic: Cell::new(Instant::now()),
+                                       notif_handles: Vec::new(), coalescer, in_flight: 0});
         Ok(())
     }
 
+    /// Split a notification for a client into one message per sample, and
+    /// pass them through the client's coalescer.
+    fn coalesce_notification(&mut self, index: usize, msg: AdsMessage) {
+        let source_port = msg.get_source_port();
+        let notif = match ads::notif::Notification::new(msg.0) {
+            Ok(notif) => notif,
+            Err(err) => {
+                warn!("dropping invalid notification: {err}");
+                return;
+            }
+        };
+        let client = &mut self.clients[index];
+        let source = AmsAddr::new(client.clients_bh_addr.netid(), source_port);
+        for sample in notif.samples() {
+            let mut notif_data = NotifData::new();
+            notif_data.add_stamp(sample.timestamp, &[(sample.handle, sample.data)]);
+            let frame = AdsMessage::new(client.client_addr, source, NOTIF, false, 0,
+                                        notif_data.data());
+            match client.coalescer.as_mut().map(|c| c.offer(sample.handle, frame)) {
+                Some(Offer::Send(frame)) => {
+                    client.last_traffic.set(Instant::now());
+                    if let Err(err) = (&client.sock).write_all(&frame.0) {
+                        warn!("error forwarding notification to client: {err}");
+                    }
+                }
+                Some(Offer::Replaced) => self.stats.lock().unwrap().notifications_dropped += 1,
+                Some(Offer::Held) | None => {}
+            }
+        }
+    }
+
+    /// Send coalesced notifications whose window has passed.
+    fn flush_notifications(&mut self) {
+        for client in &mut self.clients {
+            if let Some(coalescer) = &mut client.coalescer {
+                for frame in coalescer.due() {
+                    if client.used {
+                        client.last_traffic.set(Instant::now());
+                        if let Err(err) = (&client.sock).write_all(&frame.0) {
+                            warn!("error forwarding notification to client: {err}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Close client connections without any traffic for the given time.
+    /// The reader thread then notices the closed socket and the client is
+    /// removed as usual.
+    fn close_idle_clients(&self, timeout: Duration) {
+        for client in &self.clients {
+            let idle = client.last_traffic.get().elapsed();
+            if client.used && idle >= timeout {
+                info!("closing connection from {}, idle for {idle:.1?}", client.peer);
+                let _ = client.sock.shutdown(Shutdown::Both);
+                // don't close again until the reader has reported it
+                client.last_traffic.set(Instant::now());
+            }
+        }
+    }
+
+    /// Close all connections and remove the routes we added on the Beckhoff.
+    fn shutdown(&self, bh_sock: &mut TcpStream) {
+        for client in &self.clients {
+            if client.used {
+                let _ = client.sock.shutdown(Shutdown::Both);
+            }
+        }
+        info!("exiting, removing routes...");
+        if let Err(err) = self.bh.remove_routes(bh_sock, &self.local_ams_net_id, "forwarder") {
+            warn!("could not remove forwarder route: {err:#}");
+        }
+        if let Err(err) = self.bh.remove_routes(bh_sock, &self.local_ams_net_id, "fwdclient") {
+            warn!("could not remove forwarder client routes: {err:#}");
+        }
+        let _ = bh_sock.shutdown(Shutdown::Both);
+    }
+
+    /// Make a client's virtual NetID available for new clients.
+    fn release_id(&mut self, virtual_id: AmsNetId) {
+        let cid = virtual_id.0[3];
+        if cid != 0 {
+            self.ids.push(cid);
+        }
+    }
+
+    /// Number of client requests forwarded to the Beckhoff that have not
+    /// been answered yet.
+    fn in_flight(&self) -> usize {
+        if self.single_ams_net_id {
+            self.invoke_id_to_client_map.len()
+        } else {
+            // a client that quit takes its count with it
+            self.clients.iter().map(|c| c.in_flight).sum()
+        }
+    }
+
+    /// Answer a request that is not allowed to pass with an error reply,
+    /// so that the client doesn't wait for it.
+    fn reject_request(&self, request: &AdsMessage, index: usize) {
+        let client = &self.clients[index];
+        let cmd = request.get_command();
+        warn!("not forwarding {} request from {}", cmd.name(), client.peer);
+        let mut data = Vec::new();
+        data.write_u32::<LE>(ADSERR_NOT_PERMITTED).unwrap();
+        if !matches!(cmd, AdsCommand::Write | AdsCommand::WriteControl |
+                     AdsCommand::DeleteNotification) {
+            // length of the returned data, or the notification handle
+            data.write_u32::<LE>(0).unwrap();
+        }
+        let reply = AdsMessage::new(request.get_source(), request.get_dest(), cmd.into(),
+                                    true, request.get_invoke_id(), &data);
+        if let Err(err) = (&client.sock).write_all(&reply.0) {
+            warn!("error sending error reply to client: {err}");
+        }
+    }
+
     /// Handles a message coming from the Beckhoff intended for the given client.
     fn msg_from_beckhoff(&self, mut reply: AdsMessage, client: &ClientConn) {
-        reply.patch_source_id(client.clients_bh_id);
-        reply.patch_dest_id(client.client_id);
-        reply.patch_dest_port(client.client_source_port);
+        let source = self.aliases.translate_incoming(reply.get_source_id())
+                                 .unwrap_or_else(|| client.clients_bh_addr.netid());
+        reply.patch_source_id(source);
+        reply.patch_dest(&client.client_addr);
          if self.summarize {
              reply.summarize(InOutClientBH::OutToClnt, self.dump);
          }
         if reply.0.len() == 0xae && reply.0[0x6e..0x74] == client.virtual_id.0 {
             info!("mangling NetID in 'login' query");
-            reply.0[0x6e..0x74].copy_from_slice(&client.client_id.0);
+            reply.0[0x6e..0x74].copy_from_slice(&client.client_addr.netid().0);
+        }
+        if self.log_messages {
+            log_message("Beckhoff -> client", &reply, self.dump);
         }
+        client.last_traffic.set(Instant::now());
         // if the socket is closed, the next read attempt will return Quit
         // and the client will be dropped, so only log send failures here
         if let Err(err) = (&client.sock).write_all(&reply.0) {
@@ -643,14 +1205,17 @@ impl Distributor {
             info!("From Client =========================================");
             request.summarize(InOutClientBH::InFrmClnt, self.dump);
         }
+        self.clients[index].last_traffic.set(Instant::now());
+        if !request.is_reply() && !self.command_filter.allows(request.get_command()) {
+            self.reject_request(&request, index);
+            return;
+        }
         // first request: remember NetIDs of the requests
         let client = &mut self.clients[index];
-        if client.client_id.is_zero() {
+        if client.client_addr.netid().is_zero() {
             info!("client {} has NetID {}", client.peer, request.get_source_id());
-            client.client_id = request.get_source_id();
-            client.client_source_port = request.get_source_port();
-            client.clients_bh_id = request.get_dest_id();
-            client.clients_bh_dest_port = request.get_dest_port();
+            client.client_addr = request.get_source();
+            client.clients_bh_addr = request.get_dest();
 
             if !self.single_ams_net_id {
                 if let Err(err) = self.bh.add_route(client.virtual_id, "fwdclient") {
@@ -660,6 +1225,9 @@ impl Distributor {
                 }
             }
         }
+        if let Some(device_id) = self.aliases.translate_outgoing(request.get_dest_id()) {
+            request.patch_dest_id(device_id);
+        }
         if self.bh.typ == BhType::BC {
             request.patch_dest_id(self.bh.netid);
         }
@@ -697,10 +1265,8 @@ impl Distributor {
                                 data.write_u32::<LE>(result).unwrap();
                                 data.write_u32::<LE>(*handle).unwrap();
                                 let is_reply = true;
-                                let reply_msg = AdsMessage::new(request.get_source_id(),
-                                                                request.get_source_port(),
-                                                                request.get_dest_id(),
-                                                                request.get_dest_port(),
+                                let reply_msg = AdsMessage::new(request.get_source(),
+                                                                request.get_dest(),
                                                                 request.get_cmd(),
                                                                 is_reply,
                                                                 request.get_invoke_id(),
@@ -721,11 +1287,9 @@ impl Distributor {
                                     /***************/
                                     let is_reply = false;
                                     let invoke_id = 0;
-                                    let noti_msg = AdsMessage::new(request.get_source_id(),
-                                                                   request.get_source_port(),
-                                                                   request.get_dest_id(),
-                                                                   request.get_dest_port(),
-                                                                   8,
+                                    let noti_msg = AdsMessage::new(request.get_source(),
+                                                                   request.get_dest(),
+                                                                   NOTIF,
                                                                    is_reply,
                                                                    invoke_id,
                                                                    notif_data.data());
@@ -756,7 +1320,14 @@ impl Distributor {
                 // Delete notification
                 let mut answer_client_do_not_talk_to_beckhoff = true;
                 let len = request.get_length();
-                let handle = LE::read_u32(&request.0[38..]);
+                let handle = match request.get_data().get(..4) {
+                    Some(data) => LE::read_u32(data),
+                    None => {
+                        warn!("dropping DELNOTIF request without a handle from {}",
+                              self.clients[index].peer);
+                        return;
+                    }
+                };
                 info!("get_event cmd=DELNOTIF len={len} handle={handle}");
                 match self.notif_handle_to_client_indices_map.get_mut(&handle) {
                     Some(notif_indices) => {
@@ -793,10 +1364,8 @@ impl Distributor {
                 }
                 if answer_client_do_not_talk_to_beckhoff {
                     let is_reply = true;
-                    let reply_msg = AdsMessage::new(request.get_source_id(),
-                                                    request.get_source_port(),
-                                                    request.get_dest_id(),
-                                                    request.get_dest_port(),
+                    let reply_msg = AdsMessage::new(request.get_source(),
+                                                    request.get_dest(),
                                                     request.get_cmd(),
                                                     is_reply,
                                                     request.get_invoke_id(),
@@ -822,14 +1391,26 @@ impl Distributor {
 
             let client_req_invoke_id = ClientRequest { index,
                                                        invoke_id: invoke_id_orig,
-                                                       add_notif_req_data };
+                                                       add_notif_req_data,
+                                                       sent: Instant::now() };
             self.invoke_id_to_client_map.insert(invoke_id, client_req_invoke_id);
         } else {
             request.patch_source_id(client.virtual_id);
+            if request.get_cmd() == DELNOTIF && request.get_data().len() >= 4 {
+                let handle = LE::read_u32(request.get_data());
+                client.notif_handles.retain(|&(_, h)| h != handle);
+            }
         }
         if self.summarize {
             request.summarize(InOutClientBH::OutToBeck, self.dump);
         }
+        if self.log_messages {
+            log_message("client -> Beckhoff", &request, self.dump);
+        }
+        self.stats.lock().unwrap().record(Direction::ToBeckhoff, &request);
+        if !self.single_ams_net_id && !request.is_reply() {
+            self.clients[index].in_flight += 1;
+        }
         // if the socket is closed, the next read attempt will return Quit
         // and the connection will be reopened
         if let Err(err) = bh_sock.write_all(&request.0) {
@@ -841,7 +1422,16 @@ impl Distributor {
 
 impl Forwarder {
     pub fn new(opts: Options, bh: Beckhoff) -> Self {
-        Forwarder { opts, bh }
+        Forwarder { opts, bh, stats: Default::default() }
+    }
+
+    /// TCP keepalive settings from the options, `None` if disabled.
+    fn keepalive(&self) -> Option<(Duration, Duration)> {
+        if self.opts.tcp_keepalive == 0 {
+            return None;
+        }
+        Some((Duration::from_secs(self.opts.tcp_keepalive),
+              Duration::from_secs(self.opts.tcp_keepalive_interval)))
     }
 
     /// Run the UDP forwarder on a given UDP port.
@@ -849,7 +1439,13 @@ impl Forwarder {
     /// Since the BC and CX models use different UDP ports and protocols,
     /// we start two of these.
     fn run_udp(&self, name: &'static str, port: u16) -> Result<()> {
-        let sock = UdpSocket::bind(("0.0.0.0", port)).context("binding UDP socket")?;
+        // use the same address as for TCP, if one was given; the Beckhoff
+        // protocols are IPv4 only
+        let ip = match self.opts.listen {
+            Some(SocketAddr::V4(addr)) => *addr.ip(),
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let sock = UdpSocket::bind((ip, port)).context("binding UDP socket")?;
         sock.set_broadcast(true)?;
         info!("{name}: bound to {}", sock.local_addr()?);
 
@@ -869,7 +1465,7 @@ impl Forwarder {
                         }
                         info!("{len} bytes client -> Beckhoff");
                         if dump {
-                            hexdump(&buf[..len]);
+                            hexdump_colored(&buf[..len]);
                         }
                         if let Err(err) = sock.send_to(&buf[..len], (bh_ip, port)) {
                             warn!("error forwarding request to Beckhoff: {err}");
@@ -877,7 +1473,7 @@ impl Forwarder {
                     } else {
                         info!("{len} bytes Beckhoff -> client");
                         if dump {
-                            hexdump(&buf[..len]);
+                            hexdump_colored(&buf[..len]);
                         }
                         if let Err(err) = sock.send_to(&buf[..len], active_client) {
                             warn!("error forwarding request to client: {err}");
@@ -896,16 +1492,49 @@ impl Forwarder {
             .expect("register signal");
         signal_hook::flag::register(signal_hook::consts::SIGTERM, atomic.clone())
             .expect("register signal");
+        let dump_stats = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, dump_stats.clone())
+            .expect("register signal");
+
+        let command_filter = if !self.opts.allow_command.is_empty() {
+            CommandFilter::Allow(self.opts.allow_command.clone())
+        } else {
+            let mut deny = self.opts.deny_command.clone();
+            if self.opts.read_only {
+                deny.extend([AdsCommand::Write, AdsCommand::WriteControl]);
+            }
+            if deny.is_empty() { CommandFilter::All } else { CommandFilter::Deny(deny) }
+        };
+
+        let mut aliases = NetIdMap::default();
+        for &(client_id, device_id) in &self.opts.netid_alias {
+            aliases.register(client_id, device_id);
+        }
 
         debug!("run_tcp_distributor local_ams_net_id={:?}", self.opts.local_ams_net_id);
         Distributor {
             bh: self.bh.clone(),
             ids: (1..255).rev().collect(),
+            pending_cleanup: HashMap::new(),
             dump: self.opts.dump,
             summarize: self.opts.summarize,
+            log_messages: self.opts.log_messages,
             single_ams_net_id: self.opts.single_ams_net_id,
             local_ams_net_id: self.opts.local_ams_net_id.unwrap_or(FWDER_NETID),
             sig: atomic,
+            dump_stats,
+            stats: self.stats.clone(),
+            command_filter,
+            aliases,
+            keepalive: self.keepalive(),
+            idle_timeout: self.opts.idle_timeout.map(Duration::from_secs),
+            coalesce_window: self.opts.coalesce_notifications.map(Duration::from_millis),
+            coalesce_exempt: self.opts.coalesce_exempt.iter().map(|&ip| ip.into()).collect(),
+            max_message_len: self.opts.max_message_len,
+            rate_limit_frames: self.opts.rate_limit_frames,
+            rate_limit_bytes: self.opts.rate_limit_bytes,
+            grace: Duration::from_secs(self.opts.shutdown_grace),
+            shutdown_deadline: None,
             clients: Vec::with_capacity(4),
             invoke_id_client_req: 0,
             invoke_id_our_req: 0,
@@ -921,16 +1550,23 @@ impl Forwarder {
     /// Run the TCP listener, sending new client connections to the given channel.
     fn run_tcp_listener(&mut self, conn_tx: Sender<TcpStream>) -> Result<()> {
         // listen for incoming connections
-        let srv_sock = TcpListener::bind(("0.0.0.0", BECKHOFF_TCP_PORT))
-            .context("binding TCP socket")?;
+        let addr = self.opts.listen
+            .unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, BECKHOFF_TCP_PORT).into());
+        let srv_sock = TcpListener::bind(addr).context("binding TCP socket")?;
         info!("TCP: bound to {}", srv_sock.local_addr()?);
 
+        let keepalive = self.keepalive();
         spawn("listener", move || {
             // main loop: send new client sockets to distributor
             for conn in srv_sock.incoming().flatten() {
-                if conn.set_nodelay(true).is_ok() {
-                    let _ = conn_tx.send(conn);
+                // the connection still works without these, so don't refuse it
+                if let Err(err) = conn.set_nodelay(true) {
+                    warn!("TCP: could not set nodelay on client connection: {err}");
                 }
+                if let Err(err) = set_keepalive(&conn, keepalive) {
+                    warn!("TCP: could not enable keepalive on client connection: {err}");
+                }
+                let _ = conn_tx.send(conn);
             }
         });
         Ok(())
@@ -954,6 +1590,10 @@ impl Forwarder {
             {
                 bail!("TCP: while adding backroute: {err}");
             }
+            #[cfg(feature = "metrics")]
+            if let Some(addr) = self.opts.metrics_addr {
+                crate::metrics::run_metrics_server(addr, self.stats.clone())?;
+            }
             // start TCP forwarding
             let (conn_tx, conn_rx) = crossbeam_channel::unbounded();
             self.run_tcp_listener(conn_tx)?;
@@ -0,0 +1,189 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+use std::net;
+
+use ads::AmsNetId;
+use clap::Parser;
+
+// helpers for the forwarder's own ADS requests, for use in scripts and checks
+pub mod client;
+pub mod config;
+pub mod scanner;
+pub mod forwarder;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod stats;
+pub mod util;
+
+use crate::util::{AdsCommand, BECKHOFF_BC_UDP_PORT, BECKHOFF_TCP_PORT, BECKHOFF_UDP_PORT,
+                  DEFAULT_MAX_MESSAGE_LEN, parse_dscp, parse_keepalive_interval,
+                  parse_netid_alias, parse_rate, parse_scan_bufsize};
+
+
+/// A forwarder for Beckhoff ADS and UDP connections.
+#[derive(Parser)]
+#[clap(author, version, about)]
+pub struct Options {
+    #[clap(short='F', long="forward", help="Forward connections (only scan otherwise)")]
+    pub forward: bool,
+    #[clap(short='U', long="udp-only", help="Forward only UDP")]
+    pub udponly: bool,
+    #[clap(short='s', long="summarize", help="Summarize TCP packets")]
+    pub summarize: bool,
+    #[clap(short='l', long="log-messages",
+           help="Log one line for each forwarded TCP packet, with -d also its payload")]
+    pub log_messages: bool,
+    #[clap(short='S', long="single-ams-net-id", help="Use only one AMS Net ID towards Beckhoff")]
+    pub single_ams_net_id: bool,
+    #[clap(short='d', long="dump", help="Hexdump TCP and UDP packets")]
+    pub dump: bool,
+    #[clap(long="json", help="Print scan results as JSON")]
+    pub json: bool,
+    #[clap(long="hosts", conflicts_with="json", help="Print scan results as /etc/hosts lines")]
+    pub hosts: bool,
+    #[clap(long="sort", arg_enum, default_value="netid", help="Sort scan results by this key")]
+    pub sort: SortKey,
+    #[clap(long="limit", help="Show only the first N scan results")]
+    pub limit: Option<usize>,
+    #[clap(long="cache", help="Compare scan results with those saved in this file, then \
+                               update it")]
+    pub cache: Option<std::path::PathBuf>,
+    #[clap(long="known-devices",
+           help="JSON file remembering the address of each NetID across runs")]
+    pub known_devices: Option<std::path::PathBuf>,
+    #[clap(long="watch", help="Scan every N seconds and report Beckhoffs appearing and leaving")]
+    pub watch: Option<u64>,
+    #[clap(long="watch-missing", default_value="3",
+           help="Number of missed scans before a Beckhoff counts as gone in watch mode")]
+    pub watch_missing: u32,
+    #[clap(long="config", help="TOML file with settings; command line options take precedence")]
+    pub config: Option<std::path::PathBuf>,
+    #[clap(long="listen",
+           help="Address to listen for ADS TCP connections on (default 0.0.0.0:48898); \
+                 the UDP ports are bound on the same IPv4 address")]
+    pub listen: Option<net::SocketAddr>,
+    #[clap(short='v', long="verbose", help="Show debug log messages")]
+    pub verbose: bool,
+    #[clap(long="scan-timeout", default_value="500", help="Time to wait for scan replies in ms")]
+    pub scan_timeout: u64,
+    #[clap(long="scan-retries", default_value="0", help="Number of times to repeat the scan")]
+    pub scan_retries: u8,
+    #[clap(long="bc-udp-port", default_value_t=BECKHOFF_BC_UDP_PORT, help="UDP port to scan for BCs")]
+    pub bc_udp_port: u16,
+    #[clap(long="udp-port", default_value_t=BECKHOFF_UDP_PORT, help="UDP port to scan for CXs")]
+    pub udp_port: u16,
+    #[clap(long="tcp-port", default_value_t=BECKHOFF_TCP_PORT,
+           help="TCP port to find the route to scanned Beckhoffs")]
+    pub tcp_port: u16,
+    #[clap(long="scan-source-netid", help="Source NetID for the CX scan packet")]
+    pub scan_source_netid: Option<AmsNetId>,
+    #[clap(long="scan-source-port", help="Source AMS port for the CX scan packet")]
+    pub scan_source_port: Option<u16>,
+    #[clap(long="scan-udp-port", default_value="0",
+           help="Local UDP port to send scan packets from (default ephemeral)")]
+    pub scan_udp_port: u16,
+    #[clap(long="scan-ttl", help="IP TTL for scan packets (default from the OS)")]
+    pub scan_ttl: Option<u32>,
+    #[clap(long="scan-dscp", parse(try_from_str=parse_dscp),
+           help="DSCP value (0-63) to mark scan packets with")]
+    pub scan_dscp: Option<u8>,
+    #[clap(long="scan-bufsize", default_value="2048", parse(try_from_str=parse_scan_bufsize),
+           help="Buffer size for scan replies (at least 64)")]
+    pub scan_bufsize: usize,
+    #[clap(long="exclude", help="IP address to leave out when scanning (can be repeated)")]
+    pub exclude: Vec<net::Ipv4Addr>,
+    #[clap(long="directed-broadcast", help="Scan interfaces using their subnet broadcast address \
+                                           instead of 255.255.255.255")]
+    pub directed_broadcast: bool,
+    #[clap(long="multicast", conflicts_with="directed_broadcast",
+           help="Scan interfaces by sending to this multicast group instead of broadcasting")]
+    pub multicast: Option<net::Ipv4Addr>,
+    #[clap(long="only-bc", conflicts_with="only_cx", help="Only scan for BC bus couplers")]
+    pub only_bc: bool,
+    #[clap(long="only-cx", help="Only scan for CX controllers")]
+    pub only_cx: bool,
+    #[clap(long="local-ams-net-id")]
+    pub local_ams_net_id: Option<AmsNetId>,
+    #[cfg(feature = "metrics")]
+    #[clap(long="metrics-addr", help="Serve Prometheus metrics via HTTP on this address")]
+    pub metrics_addr: Option<net::SocketAddr>,
+    #[clap(long="allow-command", conflicts_with_all=&["deny_command", "read_only"],
+           help="ADS command to forward, all others are rejected (by name, e.g. Read or \
+                ReadDeviceInfo, or number; can be repeated)")]
+    pub allow_command: Vec<AdsCommand>,
+    #[clap(long="deny-command",
+           help="ADS command to reject instead of forwarding (can be repeated)")]
+    pub deny_command: Vec<AdsCommand>,
+    #[clap(long="read-only", help="Reject Write and WriteControl requests from clients")]
+    pub read_only: bool,
+    #[clap(long="tcp-keepalive", default_value="30",
+           help="Seconds of silence before TCP keepalive probes are sent (0 to disable)")]
+    pub tcp_keepalive: u64,
+    #[clap(long="tcp-keepalive-interval", default_value="10",
+           parse(try_from_str=parse_keepalive_interval),
+           help="Seconds between TCP keepalive probes")]
+    pub tcp_keepalive_interval: u64,
+    #[clap(long="coalesce-notifications",
+           help="Forward at most one notification per handle every N ms to each client")]
+    pub coalesce_notifications: Option<u64>,
+    #[clap(long="coalesce-exempt",
+           help="Client IP that gets every notification despite --coalesce-notifications \
+                 (can be repeated)")]
+    pub coalesce_exempt: Vec<net::Ipv4Addr>,
+    #[clap(long="idle-timeout", help="Close client connections idle for N seconds")]
+    pub idle_timeout: Option<u64>,
+    #[clap(long="max-message-len", default_value_t=DEFAULT_MAX_MESSAGE_LEN,
+           help="Close connections sending TCP packets larger than this many bytes")]
+    pub max_message_len: usize,
+    #[clap(long="rate-limit-frames", parse(try_from_str=parse_rate),
+           help="Maximum TCP packets per second accepted from each client")]
+    pub rate_limit_frames: Option<u32>,
+    #[clap(long="rate-limit-bytes", parse(try_from_str=parse_rate),
+           help="Maximum bytes per second accepted from each client")]
+    pub rate_limit_bytes: Option<u32>,
+    #[clap(long="shutdown-grace", default_value="5",
+           help="Seconds to wait for in-flight requests when stopped by a signal")]
+    pub shutdown_grace: u64,
+    #[clap(long="netid-alias", parse(try_from_str=parse_netid_alias),
+           help="Let clients address the Beckhoff NetID DEVICE as CLIENT, given as \
+                 CLIENT=DEVICE (can be repeated)")]
+    pub netid_alias: Vec<(AmsNetId, AmsNetId)>,
+    #[clap(long="add-route", conflicts_with="forward",
+           help="Add a route for the forwarder on all found Beckhoffs")]
+    pub add_route: bool,
+    #[clap(long="route-user", default_value="Administrator", help="User name for --add-route")]
+    pub route_user: String,
+    #[clap(long="route-password", help="Password for --add-route (default: try the standard ones)")]
+    pub route_password: Option<String>,
+    #[clap(help="Interface, IP (or comma-separated IPs), subnet, AMS NetID or hostname to scan \
+                  (default all interfaces)")]
+    pub target: Option<String>,
+}
+
+/// Keys to sort the scan results by.
+#[derive(clap::ArgEnum, Clone, Copy)]
+pub enum SortKey {
+    Ip,
+    Netid,
+    Name,
+}
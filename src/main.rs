@@ -20,43 +20,31 @@
 //
 // *****************************************************************************
 
-use std::{net, process};
+use std::{net, process, time::Duration};
 use log::{debug, info, error};
 use clap::Parser;
 use atty::Stream;
 
-mod scanner;
-mod forwarder;
-mod util;
+use ads_forwarder::{config, forwarder, Options, SortKey};
+use ads_forwarder::scanner::{DeviceFilter, KnownDevices, Scan, Scanner, WatchEvent,
+                             format_hosts, format_table};
+use ads_forwarder::util::{FWDER_NETID, parse_cidr};
 
-use ads::{AmsNetId};
-use crate::scanner::{Scan, Scanner};
 
-
-/// A forwarder for Beckhoff ADS and UDP connections.
-#[derive(Parser)]
-#[clap(author, version, about)]
-pub struct Options {
-    #[clap(short='F', long="forward", help="Forward connections (only scan otherwise)")]
-    forward: bool,
-    #[clap(short='U', long="udp-only", help="Forward only UDP")]
-    udponly: bool,
-    #[clap(short='s', long="summarize", help="Summarize TCP packets")]
-    summarize: bool,
-    #[clap(short='S', long="single-ams-net-id", help="Use only one AMS Net ID towards Beckhoff")]
-    single_ams_net_id: bool,
-    #[clap(short='d', long="dump", help="Hexdump TCP and UDP packets")]
-    dump: bool,
-    #[clap(short='v', long="verbose", help="Show debug log messages")]
-    verbose: bool,
-    #[clap(long="local-ams-net-id")]
-    local_ams_net_id: Option<AmsNetId>,
-    #[clap(help="Interface, IP, AMS NetID or hostname to scan (default all interfaces)")]
-    target: Option<String>,
+/// Parse a comma-separated list of IP addresses.
+fn parse_addr_list(what: &str) -> Option<Vec<net::Ipv4Addr>> {
+    if !what.contains(',') {
+        return None;
+    }
+    what.split(',').map(|addr| addr.trim().parse().ok()).collect()
 }
 
 fn main() {
     let mut opts = Options::from_args();
+    let config = opts.config.as_deref().map(config::Config::load).transpose();
+    if let Ok(Some(config)) = &config {
+        config.apply(&mut opts);
+    }
     mlzlog::init(None::<&str>, "ads_forwarder",
                  mlzlog::Settings {
                      show_appname: false,
@@ -64,32 +52,152 @@ fn main() {
                      debug: opts.verbose,
                      ..Default::default()
                  }).unwrap();
+    let config = match config {
+        Ok(config) => config.unwrap_or_default(),
+        Err(err) => {
+            error!("could not load config: {err:#}");
+            process::exit(1);
+        }
+    };
 
     let what = opts.target.take().unwrap_or_default();
-    let scanner = Scanner::new(opts.dump);
+    let addr_list;
+    let filter = if opts.only_bc {
+        DeviceFilter::OnlyBC
+    } else if opts.only_cx {
+        DeviceFilter::OnlyCX
+    } else {
+        DeviceFilter::All
+    };
+    let scanner = Scanner::with_timeout(opts.dump, Duration::from_millis(opts.scan_timeout))
+        .retries(opts.scan_retries)
+        .filter(filter)
+        .ports(opts.bc_udp_port, opts.udp_port, opts.tcp_port)
+        .probe_source(opts.scan_source_netid, opts.scan_source_port)
+        .exclude(std::mem::take(&mut opts.exclude))
+        .bufsize(opts.scan_bufsize)
+        .directed_broadcast(opts.directed_broadcast)
+        .multicast(opts.multicast)
+        .source_port(opts.scan_udp_port)
+        .ttl(opts.scan_ttl)
+        .dscp(opts.scan_dscp);
 
     // check out what argument was given (interface, IP address, NetID),
     // and scan for Beckhoffs an their NetIDs
-    let mut beckhoffs = if scanner.if_exists(&what) {
+    let target = if scanner.if_exists(&what) {
         debug!("scanning interface {what}");
-        scanner.scan(Scan::Interface(&what))
+        Scan::Interface(&what)
     } else if let Ok(addr) = what.parse::<net::Ipv4Addr>() {
         debug!("scanning IP address {addr}");
-        scanner.scan(Scan::Address(addr))
+        Scan::Address(addr)
+    } else if let Some(addrs) = parse_addr_list(&what) {
+        debug!("scanning {} IP addresses", addrs.len());
+        addr_list = addrs;
+        Scan::Addresses(&addr_list)
+    } else if what.contains('/') {
+        match parse_cidr(&what) {
+            Ok((addr, mask)) => {
+                debug!("scanning subnet {addr}/{mask}");
+                Scan::Subnet(addr, mask)
+            }
+            Err(err) => {
+                error!("invalid subnet {what}: {err}");
+                process::exit(1);
+            }
+        }
     } else if let Ok(netid) = what.parse::<ads::AmsNetId>() {
         debug!("scanning for AMS NetId {netid}");
-        scanner.scan(Scan::NetId(netid))
+        Scan::NetId(netid)
     } else if let Some(addr) = mlzutil::net::lookup_ipv4(&what) {
         debug!("scanning host {what}");
-        scanner.scan(Scan::Address(addr))
+        Scan::Address(addr)
     } else if what.is_empty() {
         debug!("scanning everything");
-        scanner.scan(Scan::Everything)
+        Scan::Everything
     } else {
-        error!("argument must be a valid interface, IP or NetID");
+        error!("argument must be a valid interface, IP, list of IPs, subnet or NetID");
         process::exit(1);
     };
 
+    if let Some(interval) = opts.watch {
+        scanner.watch(target, Duration::from_secs(interval), opts.watch_missing,
+                      |event| match event {
+                          WatchEvent::Added(bh) => info!("watch: {bh} appeared"),
+                          WatchEvent::Removed(netid) => info!("watch: {netid} disappeared"),
+                      });
+    }
+
+    let mut known = opts.known_devices.as_deref().map(KnownDevices::load);
+    // a known NetID only needs its entry checked, not a full scan
+    let cached = match (target, &known) {
+        (Scan::NetId(netid), Some(known)) => known.get(netid).and_then(|(bh, seen)| {
+            debug!("scan: {netid} was last seen at {} (timestamp {seen})", bh.bh_addr);
+            scanner.refresh(bh).filter(|bh| bh.netid == netid)
+        }),
+        _ => None,
+    };
+    let mut beckhoffs = match cached {
+        Some(bh) => vec![bh],
+        None => scanner.scan(target),
+    };
+    if let Some(known) = &mut known {
+        known.update(&beckhoffs);
+    }
+    config.check_routes(&beckhoffs);
+
+    // these sorts are stable, so equal keys stay in discovery order
+    match opts.sort {
+        SortKey::Ip => beckhoffs.sort_by_key(|bh| bh.bh_addr),
+        SortKey::Netid => beckhoffs.sort(),
+        SortKey::Name => beckhoffs.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+    if let Some(path) = &opts.cache {
+        match Scanner::load_cache(path) {
+            Ok(old) => {
+                let diff = Scanner::diff(&old, &beckhoffs);
+                for bh in &diff.added {
+                    println!("+ new {bh}");
+                }
+                for bh in &diff.removed {
+                    println!("- gone {bh}");
+                }
+                for (old, new) in &diff.changed {
+                    println!("~ changed {old} -> {new}");
+                }
+            }
+            Err(err) => info!("scan: not comparing with cache {}: {err}", path.display()),
+        }
+        if let Err(err) = Scanner::save_cache(path, &beckhoffs) {
+            error!("could not save scan cache {}: {err}", path.display());
+        }
+    }
+
+    // the limit only applies to what is shown; the cache always has everything
+    let shown = &beckhoffs[..opts.limit.unwrap_or(usize::MAX).min(beckhoffs.len())];
+
+    if opts.add_route {
+        let netid = opts.local_ams_net_id.unwrap_or(FWDER_NETID);
+        let passwords = match &opts.route_password {
+            Some(password) => vec![password.as_str()],
+            None => vec!["", "1"],
+        };
+        for bh in &beckhoffs {
+            match bh.add_route_as(netid, "forwarder", &opts.route_user, &passwords) {
+                Ok(()) => info!("added route to {netid} on {bh}"),
+                Err(err) => error!("could not add route on {bh}: {err:#}"),
+            }
+        }
+    }
+
+    if opts.hosts {
+        print!("{}", format_hosts(shown));
+    }
+
+    if opts.json {
+        let list = shown.iter().map(|bh| bh.to_json()).collect();
+        println!("{}", serde_json::Value::Array(list));
+    }
+
     if opts.forward {
         // ensure that we have only a single Beckhoff left to talk to
         if beckhoffs.len() != 1 {
@@ -102,6 +210,8 @@ fn main() {
     } else {
         if beckhoffs.is_empty() {
             info!("scan: no Beckhoff found");
+        } else if !opts.json && !opts.hosts {
+            print!("{}", format_table(shown));
         }
         info!("exiting; pass -F to forward connections")
     }
@@ -0,0 +1,136 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use mlzutil::spawn;
+
+use crate::stats::{Direction, Stats};
+use crate::util::AdsCommand;
+
+
+/// Format the statistics in the Prometheus text exposition format.
+pub fn format_metrics(stats: &Stats) -> String {
+    let mut out = String::new();
+    let dirs = [Direction::ToBeckhoff, Direction::FromBeckhoff];
+
+    out.push_str("# HELP ads_frames_total Number of forwarded ADS frames.\n");
+    out.push_str("# TYPE ads_frames_total counter\n");
+    for (&(dir, cmd), count) in &stats.commands {
+        let command = match AdsCommand::from(cmd) {
+            AdsCommand::Unknown(id) => id.to_string(),
+            command => command.name().to_string(),
+        };
+        let _ = writeln!(out, "ads_frames_total{{direction=\"{}\",command=\"{command}\"}} {count}",
+                         dir.name());
+    }
+
+    out.push_str("# HELP ads_bytes_total Number of forwarded bytes including headers.\n");
+    out.push_str("# TYPE ads_bytes_total counter\n");
+    for dir in dirs {
+        let _ = writeln!(out, "ads_bytes_total{{direction=\"{}\"}} {}", dir.name(),
+                         stats.bytes.get(&dir).copied().unwrap_or(0));
+    }
+
+    out.push_str("# HELP ads_errors_total Number of Beckhoff replies with an ADS error.\n");
+    out.push_str("# TYPE ads_errors_total counter\n");
+    for (code, count) in &stats.errors {
+        let _ = writeln!(out, "ads_errors_total{{code=\"{code:#x}\"}} {count}");
+    }
+
+    out.push_str("# HELP ads_throttled_total Number of client frames delayed by the rate limit.\n");
+    out.push_str("# TYPE ads_throttled_total counter\n");
+    let _ = writeln!(out, "ads_throttled_total {}", stats.throttled);
+
+    out.push_str("# HELP ads_notifications_dropped_total Number of notifications dropped by \
+                  coalescing.\n");
+    out.push_str("# TYPE ads_notifications_dropped_total counter\n");
+    let _ = writeln!(out, "ads_notifications_dropped_total {}", stats.notifications_dropped);
+
+    out.push_str("# HELP ads_reconnects_total Number of times the connection to the Beckhoff \
+                  was reestablished.\n");
+    out.push_str("# TYPE ads_reconnects_total counter\n");
+    let _ = writeln!(out, "ads_reconnects_total {}", stats.reconnects);
+
+    out.push_str("# HELP ads_active_connections Number of connected TCP clients.\n");
+    out.push_str("# TYPE ads_active_connections gauge\n");
+    let _ = writeln!(out, "ads_active_connections {}", stats.active_connections);
+    out
+}
+
+
+/// Answer a single scrape request with the current metrics.
+fn serve_one(mut conn: TcpStream, stats: &Mutex<Stats>) -> std::io::Result<()> {
+    conn.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // we answer every request the same, so just consume the request head
+    let mut buf = [0; 1024];
+    let mut head = Vec::new();
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    // only hold the lock as long as it takes to copy the counters
+    let snapshot = stats.lock().unwrap().snapshot();
+    let body = format_metrics(&snapshot);
+    write!(conn, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                  Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())?;
+    conn.flush()
+}
+
+
+/// Start a thread serving the statistics to Prometheus on the given address.
+pub fn run_metrics_server(addr: SocketAddr, stats: Arc<Mutex<Stats>>) -> Result<()> {
+    let srv_sock = TcpListener::bind(addr).context("binding metrics socket")?;
+    info!("metrics: bound to {}", srv_sock.local_addr()?);
+
+    spawn("metrics", move || {
+        for conn in srv_sock.incoming().flatten() {
+            if let Err(err) = serve_one(conn, &stats) {
+                debug!("metrics: error serving request: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnects_are_exported() {
+        let stats = Stats { reconnects: 3, ..Default::default() };
+        let metrics = format_metrics(&stats);
+        assert!(metrics.contains("# TYPE ads_reconnects_total counter\n"));
+        assert!(metrics.contains("\nads_reconnects_total 3\n"));
+    }
+}
@@ -21,42 +21,449 @@
 // *****************************************************************************
 
 use std::collections::HashMap;
-use std::net::{UdpSocket, TcpStream, Ipv4Addr};
-use std::time::Duration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+use std::net::{UdpSocket, TcpStream, Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use ads::{AmsAddr, AmsNetId, udp};
-use anyhow::{anyhow, Context, Result};
-use log::{debug, info, error};
-use mlzutil::{self, bytes::hexdump};
+use byteorder::{ByteOrder, LittleEndian as LE, WriteBytesExt};
+use log::{debug, info, warn, error};
+use socket2::{Domain, Protocol, Socket, Type};
+use systemstat::{IpAddr, Platform, System};
 
 use crate::forwarder::{Beckhoff, BhType};
 use crate::util::{FWDER_NETID, BECKHOFF_BC_UDP_PORT, BECKHOFF_UDP_PORT,
-                  BECKHOFF_TCP_PORT};
+                  BECKHOFF_TCP_PORT, broadcast_addr, hexdump_to_string, host_count,
+                  network_addr};
 
 
 /// Determines what to scan.
+#[derive(Clone, Copy)]
 pub enum Scan<'a> {
     Everything,
     Interface(&'a str),
     Address(Ipv4Addr),
     NetId(AmsNetId),
+    /// All host addresses of a subnet, given as address and netmask.
+    Subnet(Ipv4Addr, Ipv4Addr),
+    /// A list of individual addresses.
+    Addresses(&'a [Ipv4Addr]),
+}
+
+
+/// A change reported by `Scanner::watch`.
+pub enum WatchEvent {
+    /// A Beckhoff that was not seen before replied.
+    Added(Beckhoff),
+    /// A Beckhoff has not replied to several consecutive scans.
+    Removed(AmsNetId),
+}
+
+
+/// Differences between two scans, see `Scanner::diff`.
+pub struct ScanDiff {
+    pub added: Vec<Beckhoff>,
+    pub removed: Vec<Beckhoff>,
+    /// Same NetID, but a different address or name (old, new)
+    pub changed: Vec<(Beckhoff, Beckhoff)>,
+}
+
+
+/// The socket operations used while scanning, so that the probe and reply
+/// handling can also run on something other than a real `UdpSocket`.
+pub trait ScanSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl ScanSocket for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+
+/// NetIDs and addresses of Beckhoffs seen by earlier runs, kept in a JSON
+/// file so that known devices can be used without a full scan.
+pub struct KnownDevices {
+    path: PathBuf,
+    /// Each Beckhoff with the time it was last seen (seconds since the epoch)
+    entries: Vec<(Beckhoff, u64)>,
+}
+
+impl KnownDevices {
+    /// Load the file; a missing or invalid file starts an empty map.
+    pub fn load(path: &Path) -> KnownDevices {
+        let mut known = KnownDevices { path: path.into(), entries: Vec::new() };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                debug!("scan: no known devices from {}: {err}", path.display());
+                return known;
+            }
+        };
+        let list = serde_json::from_slice::<serde_json::Value>(&data).ok()
+            .and_then(|v| v.as_array().cloned()).unwrap_or_default();
+        for obj in &list {
+            match (Beckhoff::from_json(obj), obj.get("seen").and_then(|v| v.as_u64())) {
+                (Some(bh), Some(seen)) => known.entries.push((bh, seen)),
+                _ => warn!("scan: ignoring invalid entry in {}: {obj}", path.display()),
+            }
+        }
+        debug!("scan: {} known devices loaded", known.entries.len());
+        known
+    }
+
+    /// Return the Beckhoff last seen with this NetID, and when.
+    pub fn get(&self, netid: AmsNetId) -> Option<(&Beckhoff, u64)> {
+        self.entries.iter().find(|(bh, _)| bh.netid == netid).map(|(bh, seen)| (bh, *seen))
+    }
+
+    /// Record the Beckhoffs found now, and save the file if the NetID to
+    /// address mapping changed.
+    pub fn update(&mut self, found: &[Beckhoff]) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mut changed = false;
+        for bh in found {
+            match self.entries.iter_mut().find(|(old, _)| old.netid == bh.netid) {
+                Some((old, seen)) => {
+                    changed |= old.bh_addr != bh.bh_addr || old.typ != bh.typ;
+                    *old = bh.clone();
+                    *seen = now;
+                }
+                None => {
+                    self.entries.push((bh.clone(), now));
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            if let Err(err) = self.save() {
+                error!("could not save known devices to {}: {err}", self.path.display());
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let list = self.entries.iter().map(|(bh, seen)| {
+            let mut obj = bh.to_json();
+            obj["seen"] = (*seen).into();
+            obj
+        }).collect();
+        fs::write(&self.path, serde_json::Value::Array(list).to_string())
+    }
+}
+
+
+/// Determines which types of Beckhoff are reported by a scan.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DeviceFilter {
+    All,
+    OnlyBC,
+    OnlyCX,
+}
+
+
+/// Errors that abort a scan.
+#[derive(Debug)]
+pub enum ScanError {
+    /// The UDP socket could not be bound to the local address.
+    Bind(io::Error),
+    /// Configuring the socket or sending a scan packet failed.
+    Io(io::Error),
+    /// The requested interface does not exist or has no usable address.
+    NoInterface(String),
+    /// A reply arrived that is not a valid reply to the scan packets.
+    Parse(SocketAddr, String),
+    /// The thread scanning the given interface panicked.
+    ThreadPanic(String),
+    /// A subnet with this prefix length has too many addresses to sweep.
+    SubnetTooLarge(u32),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::Bind(err) => write!(f, "binding UDP socket: {err}"),
+            ScanError::Io(err) => write!(f, "socket error: {err}"),
+            ScanError::NoInterface(if_name) => write!(f, "no usable interface {if_name}"),
+            ScanError::Parse(addr, reason) => write!(f, "invalid reply from {addr}: {reason}"),
+            ScanError::ThreadPanic(if_name) => write!(f, "scan of interface {if_name} panicked"),
+            ScanError::SubnetTooLarge(prefix) =>
+                write!(f, "subnet /{prefix} is too large to scan, the limit is /{MIN_SUBNET_PREFIX}"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScanError::Bind(err) | ScanError::Io(err) => Some(err),
+            ScanError::NoInterface(_) | ScanError::Parse(..) | ScanError::ThreadPanic(_) |
+            ScanError::SubnetTooLarge(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScanError {
+    fn from(err: io::Error) -> Self {
+        ScanError::Io(err)
+    }
+}
+
+
+/// Default time to wait for replies to the scan packets.
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Pause between probes when sweeping many addresses, so that the socket
+/// send buffer is not overrun.
+const PROBE_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Shortest prefix length of a subnet that is swept address by address; a
+/// /16 already takes a few minutes.
+const MIN_SUBNET_PREFIX: u32 = 16;
+
+/// How often to try binding the scan socket if the address is in use, and
+/// the delay before the first retry (increasing with each retry).
+const BIND_ATTEMPTS: u32 = 4;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+
+/// An area of BC memory requested in the BC scan packet.
+struct BcArea {
+    table: u16,
+    offset: u16,
+    words: u16,
+}
+
+impl BcArea {
+    fn pack(&self, v: &mut Vec<u8>) {
+        v.write_u16::<LE>(self.table).unwrap();
+        v.write_u16::<LE>(self.offset).unwrap();
+        v.write_u16::<LE>(self.words).unwrap();
+    }
+
+    /// Length of this area in a reply: the echoed request plus the data.
+    fn reply_len(&self) -> usize {
+        6 + 2 * self.words as usize
+    }
+}
+
+/// The BC's NetID: 3 words from 0:33.
+const BC_NETID_AREA: BcArea = BcArea { table: 0, offset: 33, words: 3 };
+/// The BC's name: 10 words from 100:4.
+const BC_NAME_AREA: BcArea = BcArea { table: 100, offset: 4, words: 10 };
+
+const BC_SCAN_REQUEST: u32 = 1;
+const BC_SCAN_REPLY: u32 = 0x8000_0001;
+
+/// Build the BC scan packet, which requests the NetID and name areas.
+fn pack_bc_scan_request() -> Vec<u8> {
+    let mut v = Vec::with_capacity(16);
+    v.write_u32::<LE>(BC_SCAN_REQUEST).unwrap();
+    BC_NETID_AREA.pack(&mut v);
+    BC_NAME_AREA.pack(&mut v);
+    v
+}
+
+/// The information contained in a reply to the BC scan packet.
+struct BcScanResult {
+    netid: AmsNetId,
+    name: String,
+}
+
+impl BcScanResult {
+    /// Parse a reply, returning `None` if it does not have the expected layout.
+    fn parse(reply: &[u8]) -> Option<BcScanResult> {
+        let netid_start = 4 + 6;
+        let name_start = 4 + BC_NETID_AREA.reply_len() + 6;
+        if reply.len() != name_start + 2 * BC_NAME_AREA.words as usize ||
+            LE::read_u32(reply) != BC_SCAN_REPLY {
+            return None;
+        }
+        let netid = AmsNetId::from_slice(&reply[netid_start..netid_start + 6])?;
+        // only the first 10 bytes of the name area are used
+        let name = &reply[name_start..name_start + 10];
+        let name = String::from_utf8_lossy(
+            &name[..name.iter().position(|&ch| ch == 0).unwrap_or(10)]).into_owned();
+        Some(BcScanResult { netid, name })
+    }
 }
 
 
 pub struct Scanner {
     dump: bool,
-    if_addrs: HashMap<String, (Ipv4Addr, Ipv4Addr)>,
+    timeout: Duration,
+    retries: u8,
+    filter: DeviceFilter,
+    bc_udp_port: u16,
+    udp_port: u16,
+    tcp_port: u16,
+    probe_source: AmsAddr,
+    exclude: Vec<Ipv4Addr>,
+    bufsize: usize,
+    directed_broadcast: bool,
+    multicast: Option<Ipv4Addr>,
+    source_port: u16,
+    ttl: Option<u32>,
+    dscp: Option<u8>,
+    if_addrs: HashMap<String, Vec<(Ipv4Addr, Ipv4Addr)>>,
 }
 
 impl Scanner {
+    /// Create a scanner with the default timeout and settings.
     pub fn new(dump: bool) -> Scanner {
-        Scanner { dump, if_addrs: mlzutil::net::iface::find_ipv4_addrs() }
+        Scanner { dump, timeout: DEFAULT_SCAN_TIMEOUT, retries: 0,
+                  filter: DeviceFilter::All, bc_udp_port: BECKHOFF_BC_UDP_PORT,
+                  udp_port: BECKHOFF_UDP_PORT, tcp_port: BECKHOFF_TCP_PORT,
+                  probe_source: AmsAddr::new(FWDER_NETID, 10000), exclude: Vec::new(),
+                  bufsize: 2048, directed_broadcast: false, multicast: None, source_port: 0,
+                  ttl: None, dscp: None, if_addrs: find_usable_ipv4_addrs() }
+    }
+
+    /// Create a scanner that waits `timeout` for each reply.
+    ///
+    /// A zero timeout is rejected by the socket, so it is raised to 1 ms.
+    pub fn with_timeout(dump: bool, timeout: Duration) -> Scanner {
+        Scanner { timeout: timeout.max(Duration::from_millis(1)), ..Scanner::new(dump) }
+    }
+
+    /// Re-send the scan packets up to `retries` more times, to catch devices
+    /// whose reply (or our request) was dropped.
+    pub fn retries(mut self, retries: u8) -> Scanner {
+        self.retries = retries;
+        self
+    }
+
+    /// Only report Beckhoffs of the given type.
+    pub fn filter(mut self, filter: DeviceFilter) -> Scanner {
+        self.filter = filter;
+        self
+    }
+
+    /// Use different ports than the standard ones to contact Beckhoffs.
+    pub fn ports(mut self, bc_udp_port: u16, udp_port: u16, tcp_port: u16) -> Scanner {
+        self.bc_udp_port = bc_udp_port;
+        self.udp_port = udp_port;
+        self.tcp_port = tcp_port;
+        self
+    }
+
+    /// Use the given source address in the CX "identify" packet.
+    pub fn probe_source(mut self, netid: Option<AmsNetId>, port: Option<u16>) -> Scanner {
+        self.probe_source = AmsAddr::new(netid.unwrap_or(FWDER_NETID), port.unwrap_or(10000));
+        self
+    }
+
+    /// Never send scan packets to, or accept replies from, these addresses.
+    pub fn exclude(mut self, exclude: Vec<Ipv4Addr>) -> Scanner {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Set the size of the buffer for replies; longer replies are truncated.
+    pub fn bufsize(mut self, bufsize: usize) -> Scanner {
+        self.bufsize = bufsize;
+        self
+    }
+
+    /// Send interface scans to the subnet's broadcast address instead of
+    /// 255.255.255.255, for networks where the latter is filtered.
+    pub fn directed_broadcast(mut self, directed_broadcast: bool) -> Scanner {
+        self.directed_broadcast = directed_broadcast;
+        self
+    }
+
+    /// Send interface scans to this multicast group instead of broadcasting,
+    /// for networks where all broadcasts are filtered.
+    pub fn multicast(mut self, group: Option<Ipv4Addr>) -> Scanner {
+        self.multicast = group;
+        self
+    }
+
+    /// Send scan packets from this UDP port; 0 selects an ephemeral port.
+    pub fn source_port(mut self, port: u16) -> Scanner {
+        self.source_port = port;
+        self
+    }
+
+    /// Set the IP TTL of scan packets, for scanning across routers.
+    pub fn ttl(mut self, ttl: Option<u32>) -> Scanner {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Mark scan packets with this DSCP value, which must be at most 63.
+    pub fn dscp(mut self, dscp: Option<u8>) -> Scanner {
+        assert!(dscp.map_or(true, |dscp| dscp <= 63), "DSCP out of range: {dscp:?}");
+        self.dscp = dscp;
+        self
+    }
+
+    /// Create the socket for sending scan packets and receiving replies.
+    fn bind_socket(&self, bind_addr: Ipv4Addr) -> Result<UdpSocket, ScanError> {
+        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+            .map_err(ScanError::Bind)?;
+        // allow quick rescans from a fixed source port
+        sock.set_reuse_address(true).map_err(ScanError::Bind)?;
+        let addr = SocketAddr::from((bind_addr, self.source_port)).into();
+        // the socket of a previous scan may not be released yet
+        let mut attempt = 1;
+        while let Err(err) = sock.bind(&addr) {
+            if err.kind() != io::ErrorKind::AddrInUse || attempt == BIND_ATTEMPTS {
+                return Err(ScanError::Bind(err));
+            }
+            debug!("scan: {bind_addr}:{} is in use, retrying bind", self.source_port);
+            thread::sleep(BIND_RETRY_DELAY * attempt);
+            attempt += 1;
+        }
+        if let Some(ttl) = self.ttl {
+            sock.set_ttl(ttl)?;
+        }
+        if let Some(dscp) = self.dscp {
+            // the DSCP is the upper six bits of the TOS byte
+            sock.set_tos((dscp as u32) << 2)?;
+        }
+        Ok(sock.into())
+    }
+
+    /// Return the address to send interface scan packets to.
+    ///
+    /// On /31 point-to-point links, the peer is always contacted directly.
+    fn broadcast_for(&self, if_addr: Ipv4Addr, if_mask: Ipv4Addr) -> Ipv4Addr {
+        if let Some(group) = self.multicast {
+            group
+        } else if self.directed_broadcast || u32::from(if_mask).leading_ones() == 31 {
+            broadcast_addr(if_addr, if_mask)
+        } else {
+            Ipv4Addr::BROADCAST
+        }
     }
 
     pub fn if_exists(&self, if_name: &str) -> bool {
         self.if_addrs.contains_key(if_name)
     }
 
+    /// Return all usable interface addresses and netmasks, with the name of
+    /// their interface.
+    fn all_if_addrs(&self) -> Vec<(&str, Ipv4Addr, Ipv4Addr)> {
+        self.if_addrs.iter().flat_map(|(if_name, addrs)| {
+            addrs.iter().map(move |&(if_addr, if_mask)| (if_name.as_str(), if_addr, if_mask))
+        }).collect()
+    }
+
     /// Scan the locally reachable network for Beckhoffs.
     ///
     /// If given a `Scan::Interface`, only IPs on that interface are scanned.
@@ -64,121 +471,1003 @@ impl Scanner {
     ///
     /// Returns a vector of found Beckhoffs.
     pub fn scan(&self, what: Scan) -> Vec<Beckhoff> {
-        match self.scan_inner(what) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("during scan: {err:#}");
-                Vec::new()
+        let mut beckhoffs = Vec::new();
+        self.scan_stream(what, |bh| beckhoffs.push(bh));
+        beckhoffs
+    }
+
+    /// Like `scan`, but calls `found` for each Beckhoff as soon as its reply
+    /// has been parsed, instead of waiting for the scan to finish.
+    pub fn scan_stream(&self, what: Scan, found: impl FnMut(Beckhoff)) {
+        self.scan_with_progress(what, found, |_, _| ());
+    }
+
+    /// Like `scan_stream`, but also calls `progress` with the number of
+    /// probes sent so far and the total number of probes.
+    ///
+    /// For scans on whole interfaces, each interface counts as one probe.
+    pub fn scan_with_progress(&self, what: Scan, mut found: impl FnMut(Beckhoff),
+                              mut progress: impl FnMut(usize, usize)) {
+        let mut seen = Vec::new();
+        if let Err(err) = self.scan_inner(what, &mut |bh| {
+            seen.push((bh.netid, bh.bh_addr));
+            found(bh);
+        }, &mut progress) {
+            error!("during scan: {err}");
+        }
+        check_duplicate_netids(&seen);
+    }
+
+    /// Like `scan`, but without blocking the thread while waiting for
+    /// replies; this must be called from within a tokio runtime.
+    ///
+    /// Unlike `scan`, interfaces are scanned one after the other.
+    #[cfg(feature = "async")]
+    pub async fn scan_async(&self, what: Scan<'_>) -> Vec<Beckhoff> {
+        let mut beckhoffs = Vec::new();
+        if let Err(err) = self.scan_inner_async(what, &mut beckhoffs).await {
+            error!("during scan: {err}");
+        }
+        let seen: Vec<_> = beckhoffs.iter().map(|bh| (bh.netid, bh.bh_addr)).collect();
+        check_duplicate_netids(&seen);
+        beckhoffs
+    }
+
+    /// Save scan results as JSON, to compare against with `load_cache`.
+    pub fn save_cache(path: &Path, beckhoffs: &[Beckhoff]) -> io::Result<()> {
+        let list = beckhoffs.iter().map(|bh| bh.to_json()).collect();
+        fs::write(path, serde_json::Value::Array(list).to_string())
+    }
+
+    /// Load scan results saved by `save_cache`.
+    pub fn load_cache(path: &Path) -> io::Result<Vec<Beckhoff>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid scan cache");
+        let data: serde_json::Value = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|_| invalid())?;
+        data.as_array().ok_or_else(invalid)?
+            .iter().map(|obj| Beckhoff::from_json(obj).ok_or_else(invalid)).collect()
+    }
+
+    /// Compare two scans by NetID.
+    pub fn diff(old: &[Beckhoff], new: &[Beckhoff]) -> ScanDiff {
+        let find = |list: &[Beckhoff], bh: &Beckhoff| {
+            list.iter().find(|other| other.netid == bh.netid).cloned()
+        };
+        let mut diff = ScanDiff { added: Vec::new(), removed: Vec::new(), changed: Vec::new() };
+        for bh in new {
+            match find(old, bh) {
+                None => diff.added.push(bh.clone()),
+                Some(prev) if prev.bh_addr != bh.bh_addr || prev.name != bh.name =>
+                    diff.changed.push((prev, bh.clone())),
+                Some(_) => {}
+            }
+        }
+        for bh in old {
+            if find(new, bh).is_none() {
+                diff.removed.push(bh.clone());
             }
         }
+        diff
     }
 
-    fn scan_inner(&self, what: Scan) -> Result<Vec<Beckhoff>> {
-        let broadcast = [255, 255, 255, 255].into();
+    /// Scan repeatedly every `interval`, and call `callback` whenever a
+    /// Beckhoff appears or has been missing from `missing` consecutive scans.
+    ///
+    /// This never returns.
+    pub fn watch(&self, what: Scan, interval: Duration, missing: u32,
+                 mut callback: impl FnMut(WatchEvent)) -> ! {
+        // known Beckhoffs and the number of scans they were missing from
+        let mut known: Vec<(Beckhoff, u32)> = Vec::new();
+        loop {
+            let current = self.scan(what);
+            for (bh, missed) in &mut known {
+                if current.iter().any(|new| new.netid == bh.netid) {
+                    *missed = 0;
+                } else {
+                    *missed += 1;
+                }
+            }
+            known.retain(|(bh, missed)| {
+                if *missed >= missing.max(1) {
+                    callback(WatchEvent::Removed(bh.netid));
+                    false
+                } else {
+                    true
+                }
+            });
+            for bh in current {
+                if !known.iter().any(|(old, _)| old.netid == bh.netid) {
+                    known.push((bh.clone(), 0));
+                    callback(WatchEvent::Added(bh));
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    fn scan_inner(&self, what: Scan, found: &mut dyn FnMut(Beckhoff),
+                  progress: &mut dyn FnMut(usize, usize)) -> Result<(), ScanError> {
         match what {
             Scan::Address(bh_addr) =>
-                self.scan_addr([0, 0, 0, 0].into(), bh_addr, true),
-            Scan::Interface(if_name) =>
-                self.scan_addr(self.if_addrs[if_name].0, broadcast, false),
-            Scan::Everything => {
-                let mut all = Vec::new();
-                for (if_name, &(if_addr, _)) in &self.if_addrs {
-                    debug!("scanning interface {if_name}");
-                    all.extend(self.scan_addr(if_addr, broadcast, false)?);
+                self.scan_addrs([0, 0, 0, 0].into(), &[bh_addr], true, found, progress),
+            Scan::Interface(if_name) => {
+                let addrs = self.if_addrs.get(if_name)
+                    .ok_or_else(|| ScanError::NoInterface(if_name.into()))?;
+                // each address of the interface can be on a different subnet
+                for &(if_addr, if_mask) in addrs {
+                    let broadcast = self.broadcast_for(if_addr, if_mask);
+                    self.scan_addrs(if_addr, &[broadcast], false, found, progress)?;
+                }
+                Ok(())
+            }
+            Scan::Subnet(addr, mask) => {
+                let addrs = subnet_addrs(addr, mask)?;
+                self.scan_addrs([0, 0, 0, 0].into(), &addrs, false, found, progress)
+            }
+            Scan::Addresses(addrs) => {
+                let mut answered = Vec::new();
+                self.scan_addrs([0, 0, 0, 0].into(), addrs, false, &mut |bh| {
+                    if addrs.contains(&bh.bh_addr) {
+                        answered.push(bh.bh_addr);
+                        found(bh);
+                    } else {
+                        debug!("scan: ignoring reply from unrequested address {}", bh.bh_addr);
+                    }
+                }, progress)?;
+                for addr in addrs.iter().filter(|addr| !answered.contains(*addr)) {
+                    warn!("scan: no reply from {addr}");
                 }
-                Ok(all)
+                Ok(())
+            }
+            Scan::Everything => {
+                // scan all interface addresses in parallel, each with its own socket
+                let (tx, rx) = crossbeam_channel::unbounded();
+                let if_addrs = self.all_if_addrs();
+                thread::scope(|scope| {
+                    let handles: Vec<_> = if_addrs.into_iter().map(|(if_name, if_addr, if_mask)| {
+                        let tx = tx.clone();
+                        let broadcast = self.broadcast_for(if_addr, if_mask);
+                        (if_name, scope.spawn(move || {
+                            debug!("scanning interface {if_name} ({if_addr})");
+                            let result = self.scan_addr(if_addr, broadcast, false,
+                                                        &mut |bh| { let _ = tx.send(Some(bh)); });
+                            // signal that this interface is done
+                            let _ = tx.send(None);
+                            result
+                        }))
+                    }).collect();
+                    drop(tx);
+                    let mut seen = Vec::new();
+                    let mut done = 0;
+                    for event in rx {
+                        let bh = match event {
+                            Some(bh) => bh,
+                            None => {
+                                done += 1;
+                                progress(done, handles.len());
+                                continue;
+                            }
+                        };
+                        // a Beckhoff can be reachable via more than one interface
+                        if seen.contains(&(bh.bh_addr, bh.netid)) {
+                            debug!("scan: {bh} found on more than one interface");
+                        } else {
+                            seen.push((bh.bh_addr, bh.netid));
+                            found(bh);
+                        }
+                    }
+                    // a failing interface should not spoil the results of the others
+                    for (if_name, handle) in handles {
+                        let result = handle.join()
+                            .unwrap_or_else(|_| Err(ScanError::ThreadPanic(if_name.into())));
+                        if let Err(err) = result {
+                            warn!("during scan: scanning interface {if_name}: {err}");
+                        }
+                    }
+                    Ok(())
+                })
             }
             Scan::NetId(netid) => {
                 // scan all interfaces until we found our NetID
-                for (if_name, &(if_addr, _)) in &self.if_addrs {
-                    debug!("scanning interface {if_name}");
-                    let bhs = self.scan_addr(if_addr, broadcast, false)
-                                  .with_context(|| format!("scanning interface {if_name}"))?;
-                    if let Some(bh) = bhs.into_iter().find(|bh| bh.netid == netid) {
-                        return Ok(vec![bh]);
+                let all_if_addrs = self.all_if_addrs();
+                for (i, &(if_name, if_addr, if_mask)) in all_if_addrs.iter().enumerate() {
+                    debug!("scanning interface {if_name} ({if_addr})");
+                    let broadcast = self.broadcast_for(if_addr, if_mask);
+                    let mut matching = None;
+                    if let Err(err) = self.scan_addr(if_addr, broadcast, false, &mut |bh| if bh.netid == netid {
+                        matching = Some(bh);
+                    }) {
+                        warn!("during scan: scanning interface {if_name}: {err}");
+                    }
+                    progress(i + 1, all_if_addrs.len());
+                    if let Some(bh) = matching {
+                        found(bh);
+                        break;
                     }
                 }
-                Ok(vec![])
+                Ok(())
             }
         }
     }
 
-    fn scan_addr(&self, bind_addr: Ipv4Addr, send_addr: Ipv4Addr, single_reply: bool)
-                 -> Result<Vec<Beckhoff>> {
-        let udp = UdpSocket::bind((bind_addr, 0)).context("binding UDP socket")?;
+    fn scan_addr(&self, bind_addr: Ipv4Addr, send_addr: Ipv4Addr, single_reply: bool,
+                 found: &mut dyn FnMut(Beckhoff)) -> Result<(), ScanError> {
+        self.scan_addrs(bind_addr, &[send_addr], single_reply, found, &mut |_, _| ())
+    }
+
+    /// Send the scan packets to all given addresses from a single socket,
+    /// and collect the replies.
+    fn scan_addrs(&self, bind_addr: Ipv4Addr, send_addrs: &[Ipv4Addr], single_reply: bool,
+                  found: &mut dyn FnMut(Beckhoff), progress: &mut dyn FnMut(usize, usize))
+                  -> Result<(), ScanError> {
+        let (udp, group) = self.open_socket(bind_addr, send_addrs)?;
+        self.scan_with(&udp, send_addrs, single_reply, found, progress)?;
+        if let Some(group) = group {
+            udp.leave_multicast_v4(&group, &bind_addr)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    async fn scan_inner_async(&self, what: Scan<'_>, found: &mut Vec<Beckhoff>)
+                              -> Result<(), ScanError> {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        match what {
+            Scan::Address(bh_addr) =>
+                self.scan_addrs_async(unspecified, &[bh_addr], true, found).await,
+            Scan::Interface(if_name) => {
+                let addrs = self.if_addrs.get(if_name)
+                    .ok_or_else(|| ScanError::NoInterface(if_name.into()))?;
+                for &(if_addr, if_mask) in addrs {
+                    let broadcast = self.broadcast_for(if_addr, if_mask);
+                    self.scan_addrs_async(if_addr, &[broadcast], false, found).await?;
+                }
+                Ok(())
+            }
+            Scan::Subnet(addr, mask) => {
+                let addrs = subnet_addrs(addr, mask)?;
+                self.scan_addrs_async(unspecified, &addrs, false, found).await
+            }
+            Scan::Addresses(addrs) => {
+                let mut all = Vec::new();
+                self.scan_addrs_async(unspecified, addrs, false, &mut all).await?;
+                let (requested, other): (Vec<_>, Vec<_>) =
+                    all.into_iter().partition(|bh| addrs.contains(&bh.bh_addr));
+                for bh in other {
+                    debug!("scan: ignoring reply from unrequested address {}", bh.bh_addr);
+                }
+                let answered = |addr: &Ipv4Addr| requested.iter().any(|bh| bh.bh_addr == *addr);
+                for addr in addrs.iter().filter(|addr| !answered(addr)) {
+                    warn!("scan: no reply from {addr}");
+                }
+                found.extend(requested);
+                Ok(())
+            }
+            Scan::Everything | Scan::NetId(_) => {
+                for (if_name, if_addr, if_mask) in self.all_if_addrs() {
+                    debug!("scanning interface {if_name} ({if_addr})");
+                    let broadcast = self.broadcast_for(if_addr, if_mask);
+                    let mut on_if = Vec::new();
+                    if let Err(err) = self.scan_addrs_async(if_addr, &[broadcast], false,
+                                                            &mut on_if).await {
+                        warn!("during scan: scanning interface {if_name}: {err}");
+                    }
+                    for bh in on_if {
+                        if let Scan::NetId(netid) = what {
+                            // stop at the first interface with our NetID
+                            if bh.netid == netid {
+                                found.push(bh);
+                                return Ok(());
+                            }
+                        } else if found.iter().any(|other| other.bh_addr == bh.bh_addr &&
+                                                   other.netid == bh.netid) {
+                            debug!("scan: {bh} found on more than one interface");
+                        } else {
+                            found.push(bh);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The same as `scan_addrs` and `scan_with`, on a tokio socket.
+    #[cfg(feature = "async")]
+    async fn scan_addrs_async(&self, bind_addr: Ipv4Addr, send_addrs: &[Ipv4Addr],
+                              single_reply: bool, found: &mut Vec<Beckhoff>)
+                              -> Result<(), ScanError> {
+        let (udp, group) = self.open_socket(bind_addr, send_addrs)?;
+        udp.set_nonblocking(true)?;
+        let udp = tokio::net::UdpSocket::from_std(udp)?;
+        let mut seen = Vec::new();
+        let mut sent = HashMap::new();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                debug!("scan: retry {attempt} of {}", self.retries);
+            }
+            for (i, &send_addr) in send_addrs.iter().enumerate() {
+                if self.exclude.contains(&send_addr) {
+                    debug!("scan: not sending to excluded address {send_addr}");
+                    continue;
+                }
+                if i > 0 {
+                    tokio::time::sleep(PROBE_INTERVAL).await;
+                }
+                let bc_msg = pack_bc_scan_request();
+                udp.send_to(&bc_msg, (send_addr, self.bc_udp_port)).await?;
+                self.log_probe("BC", send_addr, &bc_msg);
+                let cx_msg = udp::Message::new(udp::ServiceId::Identify, self.probe_source);
+                udp.send_to(cx_msg.as_bytes(), (send_addr, self.udp_port)).await?;
+                self.log_probe("CX", send_addr, cx_msg.as_bytes());
+                sent.insert(send_addr, Instant::now());
+            }
+
+            let mut reply = vec![0; self.bufsize];
+            while let Ok(Ok((len, reply_addr))) =
+                tokio::time::timeout(self.timeout, udp.recv_from(&mut reply)).await
+            {
+                if let Some(bh) = self.accept_reply(&reply, len, reply_addr, send_addrs,
+                                                    &sent, &mut seen) {
+                    found.push(bh);
+                }
+                if single_reply {
+                    break;
+                }
+            }
+            if single_reply && !seen.is_empty() {
+                break;
+            }
+        }
+        if let Some(group) = group {
+            udp.leave_multicast_v4(group, bind_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Bind a broadcast-enabled socket for scanning `send_addrs`, and join
+    /// the multicast group if it is one of them.
+    ///
+    /// Returns the socket and the joined group.
+    fn open_socket(&self, bind_addr: Ipv4Addr, send_addrs: &[Ipv4Addr])
+                   -> Result<(UdpSocket, Option<Ipv4Addr>), ScanError> {
+        let udp = self.bind_socket(bind_addr)?;
         udp.set_broadcast(true)?;
-        udp.set_read_timeout(Some(Duration::from_millis(500)))?;
-
-        // scan for BCs: request 3 words from 0:33 (NetID) and 10 words from 100:4 (Name)
-        let bc_msg = [1, 0, 0, 0,
-                      0, 0, 33, 0, 3, 0,
-                      100, 0, 4, 0, 10, 0];
-        udp.send_to(&bc_msg, (send_addr, BECKHOFF_BC_UDP_PORT))
-           .context("sending BC scan broadcast")?;
-        debug!("scan: sending BC UDP packet");
-        if self.dump {
-            hexdump(&bc_msg);
+        let group = self.multicast.filter(|group| send_addrs.contains(group));
+        if let Some(group) = group {
+            debug!("scan: joining multicast group {group} on {bind_addr}");
+            udp.join_multicast_v4(&group, &bind_addr)?;
+            // we don't want to see our own probes
+            udp.set_multicast_loop_v4(false)?;
         }
+        Ok((udp, group))
+    }
 
+    /// Send the probes to the given addresses over `udp` and process the replies.
+    fn scan_with(&self, udp: &dyn ScanSocket, send_addrs: &[Ipv4Addr], single_reply: bool,
+                 found: &mut dyn FnMut(Beckhoff), progress: &mut dyn FnMut(usize, usize))
+                 -> Result<(), ScanError> {
+        udp.set_timeout(self.timeout)?;
+        let mut seen = Vec::new();
+        let mut last_new = 0;
+        // when the probes to each address were last sent, for round-trip times
+        let mut sent = HashMap::new();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                debug!("scan: retry {attempt} of {}", self.retries);
+            }
+            for (i, &send_addr) in send_addrs.iter().enumerate() {
+                if self.exclude.contains(&send_addr) {
+                    debug!("scan: not sending to excluded address {send_addr}");
+                    continue;
+                }
+                if i > 0 {
+                    thread::sleep(PROBE_INTERVAL);
+                }
+                self.send_probes(udp, send_addr)?;
+                sent.insert(send_addr, Instant::now());
+                if attempt == 0 {
+                    progress(i + 1, send_addrs.len());
+                }
+            }
+
+            // wait for replies; the loop ends once no reply arrived within the timeout
+            let mut reply = vec![0; self.bufsize];
+            while let Ok((len, reply_addr)) = udp.recv_from(&mut reply) {
+                if let Some(bh) = self.accept_reply(&reply, len, reply_addr, send_addrs,
+                                                    &sent, &mut seen) {
+                    last_new = attempt;
+                    found(bh);
+                }
+                // if scanning a single address, don't wait for more replies
+                if single_reply {
+                    break;
+                }
+            }
+            if single_reply && !seen.is_empty() {
+                break;
+            }
+        }
+        if self.retries > 0 {
+            info!("scan: {} Beckhoff(s) found, last new reply after {last_new} of {} retries",
+                  seen.len(), self.retries);
+        }
+        Ok(())
+    }
+
+    /// Handle a reply of `len` bytes received into `buf`, in answer to the
+    /// probes sent to `send_addrs` at the times in `sent`.
+    ///
+    /// Returns the Beckhoff if it has not been `seen` before.
+    fn accept_reply(&self, buf: &[u8], len: usize, reply_addr: SocketAddr,
+                    send_addrs: &[Ipv4Addr], sent: &HashMap<Ipv4Addr, Instant>,
+                    seen: &mut Vec<(Ipv4Addr, AmsNetId)>) -> Option<Beckhoff> {
+        if len == buf.len() {
+            warn!("scan: reply from {reply_addr} fills the whole buffer, probably truncated");
+        }
+        let mut bh = self.parse_reply(&buf[..len], reply_addr, None)
+            .unwrap_or_else(|err| {
+                debug!("scan: {err}");
+                None
+            })?;
+        // for broadcasts, the time is only approximate
+        let sent_at = match send_addrs {
+            [single] => sent.get(single),
+            _ => sent.get(&bh.bh_addr),
+        };
+        bh.rtt = sent_at.map(|t| t.elapsed());
+        // devices that answer more than one probe are only listed once
+        if seen.contains(&(bh.bh_addr, bh.netid)) {
+            return None;
+        }
+        seen.push((bh.bh_addr, bh.netid));
+        Some(bh)
+    }
+
+    /// Send the BC and CX scan packets to the given address.
+    fn send_probes(&self, udp: &dyn ScanSocket, send_addr: Ipv4Addr) -> io::Result<()> {
+        self.send_bc_probe(udp, send_addr)?;
+        self.send_cx_probe(udp, send_addr)
+    }
+
+    fn send_bc_probe(&self, udp: &dyn ScanSocket, send_addr: Ipv4Addr) -> io::Result<()> {
+        // scan for BCs: request the NetID and name areas
+        let bc_msg = pack_bc_scan_request();
+        udp.send_to(&bc_msg, (send_addr, self.bc_udp_port).into())?;
+        self.log_probe("BC", send_addr, &bc_msg);
+        Ok(())
+    }
+
+    fn send_cx_probe(&self, udp: &dyn ScanSocket, send_addr: Ipv4Addr) -> io::Result<()> {
         // scan for CXs: "identify" operation in the UDP protocol
-        let cx_msg = udp::Message::new(udp::ServiceId::Identify, AmsAddr::new(FWDER_NETID, 10000));
-        udp.send_to(cx_msg.as_bytes(), (send_addr, BECKHOFF_UDP_PORT))
-            .context("sending CX scan broadcast")?;
-        debug!("scan: sending CX UDP packet");
+        let cx_msg = udp::Message::new(udp::ServiceId::Identify, self.probe_source);
+        udp.send_to(cx_msg.as_bytes(), (send_addr, self.udp_port).into())?;
+        self.log_probe("CX", send_addr, cx_msg.as_bytes());
+        Ok(())
+    }
+
+    fn log_probe(&self, kind: &str, send_addr: Ipv4Addr, data: &[u8]) {
+        debug!("scan: sending {kind} UDP packet to {send_addr}");
         if self.dump {
-            hexdump(cx_msg.as_bytes());
+            debug!("{}", hexdump_to_string(data));
         }
+    }
 
-        // wait for replies
-        let mut beckhoffs = Vec::new();
-        let mut reply = [0; 2048];
-        while let Ok((len, reply_addr)) = udp.recv_from(&mut reply) {
-            let reply = &reply[..len];
-            if self.dump {
-                info!("scan: reply from {reply_addr}");
-                hexdump(reply);
-            }
-            let bh_addr = mlzutil::net::unwrap_ipv4(reply_addr.ip());
-            if reply_addr.port() == BECKHOFF_BC_UDP_PORT {
-                if reply.len() == 42 && reply[0..4] == [1, 0, 0, 0x80] {
-                    let netid = AmsNetId::from_slice(&reply[10..16]).unwrap();
-                    let name = &reply[22..32];
-                    let name = String::from_utf8_lossy(
-                        &name[..name.iter().position(|&ch| ch == 0).unwrap_or(10)]);
-                    info!("scan: found {name} ({netid}) at {bh_addr}");
-                    beckhoffs.push(Beckhoff { if_addr: self.find_if_addr(bh_addr),
-                                              typ: BhType::BC, bh_addr, netid });
-                }
-            } else if let Ok(msg) = udp::Message::parse(reply, udp::ServiceId::Identify, true) {
-                let name = msg.get_str(udp::Tag::ComputerName).unwrap_or("<???>");
-                let ver = msg.get_bytes(udp::Tag::TCVersion).ok_or_else(|| anyhow!("no version info"))?;
-                info!("scan: found {name}, TwinCat {}.{}.{} ({}) at {bh_addr}",
-                      ver[0], ver[1], ver[2] as u16 | (ver[3] as u16) << 8, msg.get_source());
-                beckhoffs.push(Beckhoff { if_addr: self.find_if_addr(bh_addr),
-                                          typ: if ver[0] == 2 { BhType::CX2 } else { BhType::CX3 },
-                                          bh_addr, netid: msg.get_source().netid() });
-            }
-            // if scanning a single address, don't wait for more replies
-            if single_reply {
-                break;
+    /// Parse a reply to one of the scan packets.
+    ///
+    /// If the interface address for the Beckhoff is already known, pass it as
+    /// `if_addr`, otherwise it is determined from the reply address.
+    ///
+    /// Returns `None` for replies that are ignored, and `ScanError::Parse` if
+    /// the reply does not have the expected layout.
+    fn parse_reply(&self, reply: &[u8], reply_addr: SocketAddr, if_addr: Option<Ipv4Addr>)
+                   -> Result<Option<Beckhoff>, ScanError> {
+        if self.dump {
+            debug!("scan: reply from {reply_addr}\n{}", hexdump_to_string(reply));
+        }
+        // the Beckhoff protocols are IPv4 only
+        let bh_addr = match reply_addr {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => {
+                debug!("scan: ignoring reply from IPv6 address {reply_addr}");
+                return Ok(None);
             }
+        };
+        let invalid = |reason: &str| ScanError::Parse(reply_addr, reason.into());
+        if self.exclude.contains(&bh_addr) {
+            debug!("scan: ignoring reply from excluded address {bh_addr}");
+            return Ok(None);
         }
-        Ok(beckhoffs)
+        let (typ, netid, name, version) = if reply_addr.port() == self.bc_udp_port {
+            if self.filter == DeviceFilter::OnlyCX {
+                debug!("scan: ignoring BC reply from {bh_addr}");
+                return Ok(None);
+            }
+            let BcScanResult { netid, name } = BcScanResult::parse(reply)
+                .ok_or_else(|| invalid("unexpected BC scan reply layout"))?;
+            info!("scan: found {name} ({netid}) at {bh_addr}");
+            (BhType::BC, netid, name, None)
+        } else {
+            if self.filter == DeviceFilter::OnlyBC {
+                debug!("scan: ignoring CX reply from {bh_addr}");
+                return Ok(None);
+            }
+            let msg = udp::Message::parse(reply, udp::ServiceId::Identify, true)
+                .map_err(|err| invalid(&err.to_string()))?;
+            let name = msg.get_str(udp::Tag::ComputerName).unwrap_or_default();
+            let ver = match msg.get_bytes(udp::Tag::TCVersion) {
+                Some(ver) if ver.len() >= 4 => ver,
+                _ => return Err(invalid("missing or short version info")),
+            };
+            let version = (ver[0], ver[1], ver[2] as u16 | (ver[3] as u16) << 8);
+            info!("scan: found {}, TwinCat {}.{}.{} ({}) at {bh_addr}",
+                  if name.is_empty() { "<???>" } else { name },
+                  version.0, version.1, version.2, msg.get_source());
+            let typ = if ver[0] == 2 { BhType::CX2 } else { BhType::CX3 };
+            (typ, msg.get_source().netid(), name.into(), Some(version))
+        };
+        let if_addr = match if_addr.or_else(|| self.find_if_addr(bh_addr)) {
+            Some(if_addr) => if_addr,
+            None => {
+                warn!("scan: no local address for route to {bh_addr}, skipping it");
+                return Ok(None);
+            }
+        };
+        Ok(Some(Beckhoff { if_addr, typ, bh_addr, netid, name, version, rtt: None }))
+    }
+
+    /// Check if a previously found Beckhoff is still there.
+    ///
+    /// Only the scan packet for the Beckhoff's type is sent, to its address.
+    /// Returns the Beckhoff with updated information, or `None` if it did not
+    /// reply.
+    pub fn refresh(&self, bh: &Beckhoff) -> Option<Beckhoff> {
+        match self.refresh_inner(bh) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("during refresh of {}: {err}", bh.bh_addr);
+                None
+            }
+        }
+    }
+
+    fn refresh_inner(&self, bh: &Beckhoff) -> Result<Option<Beckhoff>, ScanError> {
+        let udp = self.bind_socket(bh.if_addr)?;
+        self.refresh_with(&udp, bh)
+    }
+
+    /// Probe the given Beckhoff over `udp` and wait for its reply.
+    fn refresh_with(&self, udp: &dyn ScanSocket, bh: &Beckhoff)
+                    -> Result<Option<Beckhoff>, ScanError> {
+        udp.set_timeout(self.timeout)?;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                debug!("refresh: retry {attempt} of {}", self.retries);
+            }
+            if bh.typ == BhType::BC {
+                self.send_bc_probe(udp, bh.bh_addr)?;
+            } else {
+                self.send_cx_probe(udp, bh.bh_addr)?;
+            }
+            let sent_at = Instant::now();
+            let mut reply = vec![0; self.bufsize];
+            while let Ok((len, reply_addr)) = udp.recv_from(&mut reply) {
+                if reply_addr.ip() != bh.bh_addr {
+                    continue;
+                }
+                let new = self.parse_reply(&reply[..len], reply_addr, Some(bh.if_addr))
+                    .unwrap_or_else(|err| {
+                        warn!("refresh: {err}");
+                        None
+                    });
+                if let Some(mut new) = new {
+                    new.rtt = Some(sent_at.elapsed());
+                    return Ok(Some(new));
+                }
+            }
+        }
+        Ok(None)
     }
 
     /// Find the local address of the interface to connect to the given Beckhoff.
-    fn find_if_addr(&self, bh_addr: Ipv4Addr) -> Ipv4Addr {
-        // check for local IPs
-        for &(if_addr, if_mask) in self.if_addrs.values() {
+    fn find_if_addr(&self, bh_addr: Ipv4Addr) -> Option<Ipv4Addr> {
+        // check for local IPs; this includes the peer of a /31 link
+        for (_, if_addr, if_mask) in self.all_if_addrs() {
             if mlzutil::net::in_same_net(bh_addr, if_addr, if_mask) {
-                return if_addr;
+                return Some(if_addr);
             }
         }
 
         // not a local IP, check by trying to connect using TCP
-        match TcpStream::connect((bh_addr, BECKHOFF_TCP_PORT)).and_then(|sock| sock.local_addr()) {
-            Ok(addr) => mlzutil::net::unwrap_ipv4(addr.ip()),
-            _ => panic!("Did not find local address for route to Beckhoff {bh_addr}")
+        match TcpStream::connect((bh_addr, self.tcp_port)).and_then(|sock| sock.local_addr()) {
+            Ok(SocketAddr::V4(addr)) => Some(*addr.ip()),
+            _ => None,
+        }
+    }
+}
+
+/// Render a list of Beckhoffs as a table with aligned columns.
+pub fn format_table(beckhoffs: &[Beckhoff]) -> String {
+    let rows: Vec<[String; 5]> = beckhoffs.iter().map(|bh| [
+        bh.bh_addr.to_string(),
+        bh.netid.to_string(),
+        match bh.typ { BhType::BC => "BC", BhType::CX2 => "CX2", BhType::CX3 => "CX3" }.into(),
+        bh.name.clone(),
+        bh.version.map_or_else(String::new, |(maj, min, build)| format!("{maj}.{min}.{build}")),
+    ]).collect();
+    let header = ["IP", "NetID", "Type", "Name", "Version"].map(String::from);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, col) in widths.iter_mut().zip(row) {
+            *width = (*width).max(col.len());
+        }
+    }
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row.iter().zip(widths)
+            .map(|(col, width)| format!("{col:width$}")).collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Render a list of Beckhoffs as lines for /etc/hosts, named after the
+/// device name.  Beckhoffs without a usable name are skipped.
+pub fn format_hosts(beckhoffs: &[Beckhoff]) -> String {
+    let mut hosts = String::new();
+    for bh in beckhoffs {
+        let hostname = hostname_from(&bh.name);
+        if hostname.is_empty() {
+            warn!("scan: no name for {} at {}, not adding it to hosts", bh.netid, bh.bh_addr);
+            continue;
+        }
+        hosts.push_str(&format!("{}  {hostname}\n", bh.bh_addr));
+    }
+    hosts
+}
+
+/// Make a valid hostname from a device name: lowercase, with spaces and
+/// underscores replaced by dashes and other invalid characters dropped.
+fn hostname_from(name: &str) -> String {
+    let hostname: String = name.chars().filter_map(|ch| match ch {
+        ' ' | '_' | '-' => Some('-'),
+        ch if ch.is_ascii_alphanumeric() || ch == '.' => Some(ch.to_ascii_lowercase()),
+        _ => None,
+    }).collect();
+    hostname.trim_matches(|ch| ch == '-' || ch == '.').into()
+}
+
+/// Return the host addresses of a subnet, for sweeping it address by address.
+fn subnet_addrs(addr: Ipv4Addr, mask: Ipv4Addr) -> Result<Vec<Ipv4Addr>, ScanError> {
+    let network = u32::from(network_addr(addr, mask));
+    let prefix = u32::from(mask).leading_ones();
+    if prefix < MIN_SUBNET_PREFIX {
+        return Err(ScanError::SubnetTooLarge(prefix));
+    }
+    let count = host_count(mask);
+    // /31 and /32 have no network and broadcast addresses
+    let first = if prefix >= 31 { network } else { network + 1 };
+    debug!("scanning {count} addresses in {addr}/{prefix}");
+    Ok((0..count).map(|i| Ipv4Addr::from(first + i)).collect())
+}
+
+/// Warn about NetIDs that were found on more than one Beckhoff, and return
+/// them.
+///
+/// These lead to obscure routing failures, but the devices are still reported.
+fn check_duplicate_netids(seen: &[(AmsNetId, Ipv4Addr)]) -> Vec<AmsNetId> {
+    let mut duplicates = Vec::new();
+    for (i, &(netid, _)) in seen.iter().enumerate() {
+        // only report each NetID once
+        if seen[..i].iter().any(|&(other, _)| other == netid) {
+            continue;
+        }
+        let addrs: Vec<String> = seen.iter().filter(|&&(other, _)| other == netid)
+                                     .map(|(_, addr)| addr.to_string()).collect();
+        if addrs.len() > 1 {
+            warn!("scan: NetID {netid} is used by more than one Beckhoff: {}", addrs.join(", "));
+            duplicates.push(netid);
+        }
+    }
+    duplicates
+}
+
+/// Find the IPv4 addresses and netmasks of each network interface.
+fn find_ipv4_addrs() -> HashMap<String, Vec<(Ipv4Addr, Ipv4Addr)>> {
+    let networks = match System::new().networks() {
+        Ok(networks) => networks,
+        Err(err) => {
+            warn!("could not list network interfaces: {err}");
+            return HashMap::new();
         }
+    };
+    networks.into_iter().map(|(if_name, network)| {
+        // entries without a complete IPv4 address and netmask are skipped
+        let addrs = network.addrs.iter().filter_map(|addr| match (&addr.addr, &addr.netmask) {
+            (IpAddr::V4(if_addr), IpAddr::V4(if_mask)) => Some((*if_addr, *if_mask)),
+            _ => None,
+        }).collect();
+        (if_name, addrs)
+    }).collect()
+}
+
+/// Find the IPv4 addresses of all interfaces that could reach a Beckhoff,
+/// i.e. leaving out loopback and interfaces that are down.
+fn find_usable_ipv4_addrs() -> HashMap<String, Vec<(Ipv4Addr, Ipv4Addr)>> {
+    let mut if_addrs = find_ipv4_addrs();
+    if_addrs.retain(|if_name, addrs| {
+        addrs.retain(|(if_addr, _)| !if_addr.is_loopback());
+        if addrs.is_empty() {
+            return false;
+        }
+        !if_is_down(if_name)
+    });
+    debug!("usable interfaces: {:?}", if_addrs.keys().collect::<Vec<_>>());
+    if_addrs
+}
+
+/// Check if the interface is known to be down.
+#[cfg(target_os = "linux")]
+fn if_is_down(if_name: &str) -> bool {
+    // "unknown" is reported by some drivers that are up, so only skip "down"
+    let state = fs::read_to_string(format!("/sys/class/net/{if_name}/operstate"));
+    matches!(state.as_deref().map(str::trim), Ok("down"))
+}
+
+/// Check if the interface is known to be down; without sysfs, all
+/// interfaces with an address are assumed to be up.
+#[cfg(not(target_os = "linux"))]
+fn if_is_down(_if_name: &str) -> bool {
+    false
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::net::Ipv6Addr;
+
+    const IF_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const BH_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 5);
+    const BH_NETID: AmsNetId = AmsNetId::new(5, 1, 2, 3, 1, 1);
+
+    /// A socket that records the probes sent and replays canned replies.
+    struct FakeSocket {
+        sent: RefCell<Vec<(Vec<u8>, SocketAddr)>>,
+        replies: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+    }
+
+    impl FakeSocket {
+        fn new(replies: Vec<(Vec<u8>, SocketAddr)>) -> Self {
+            FakeSocket { sent: RefCell::new(Vec::new()), replies: RefCell::new(replies.into()) }
+        }
+    }
+
+    impl ScanSocket for FakeSocket {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            self.sent.borrow_mut().push((buf.to_vec(), addr));
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            // no more replies behaves like the timeout expiring
+            let (reply, addr) = self.replies.borrow_mut().pop_front()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+            let len = reply.len().min(buf.len());
+            buf[..len].copy_from_slice(&reply[..len]);
+            Ok((len, addr))
+        }
+
+        fn set_timeout(&self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A scanner with a single fake interface on 10.0.0.0/24.
+    fn scanner() -> Scanner {
+        let mut scanner = Scanner::new(false);
+        scanner.if_addrs = HashMap::from([
+            ("eth0".into(), vec![(IF_ADDR, Ipv4Addr::new(255, 255, 255, 0))]),
+        ]);
+        scanner
+    }
+
+    fn scan(scanner: &Scanner, sock: &FakeSocket, send_addrs: &[Ipv4Addr],
+            single_reply: bool) -> Vec<Beckhoff> {
+        let mut found = Vec::new();
+        scanner.scan_with(sock, send_addrs, single_reply, &mut |bh| found.push(bh),
+                          &mut |_, _| ()).unwrap();
+        found
+    }
+
+    fn cx_addr(addr: Ipv4Addr) -> SocketAddr {
+        (addr, BECKHOFF_UDP_PORT).into()
+    }
+
+    fn bc_addr(addr: Ipv4Addr) -> SocketAddr {
+        (addr, BECKHOFF_BC_UDP_PORT).into()
+    }
+
+    /// Build a reply to the CX "identify" packet.
+    fn cx_reply(name: &str, version: [u8; 4]) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.write_u32::<LE>(0x7114_6603).unwrap(); // magic
+        v.write_u32::<LE>(0).unwrap();           // invoke ID
+        v.write_u32::<LE>(0x8000_0001).unwrap(); // identify reply
+        v.extend_from_slice(&BH_NETID.0);
+        v.write_u16::<LE>(10000).unwrap();
+        v.write_u32::<LE>(2).unwrap();           // number of tags
+        v.write_u16::<LE>(5).unwrap();           // computer name
+        v.write_u16::<LE>(name.len() as u16 + 1).unwrap();
+        v.extend_from_slice(name.as_bytes());
+        v.push(0);
+        v.write_u16::<LE>(3).unwrap();           // TwinCAT version
+        v.write_u16::<LE>(4).unwrap();
+        v.extend_from_slice(&version);
+        v
+    }
+
+    /// Build a reply to the BC scan packet.
+    fn bc_reply(name: &str) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.write_u32::<LE>(BC_SCAN_REPLY).unwrap();
+        BC_NETID_AREA.pack(&mut v);
+        v.extend_from_slice(&BH_NETID.0);
+        BC_NAME_AREA.pack(&mut v);
+        let mut area = name.as_bytes().to_vec();
+        area.resize(2 * BC_NAME_AREA.words as usize, 0);
+        v.extend_from_slice(&area);
+        v
+    }
+
+    #[test]
+    fn cx_reply_is_parsed() {
+        let sock = FakeSocket::new(vec![
+            (cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]), cx_addr(BH_ADDR)),
+        ]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], false);
+        assert_eq!(found.len(), 1);
+        let bh = &found[0];
+        assert_eq!(bh.netid, BH_NETID);
+        assert_eq!(bh.bh_addr, BH_ADDR);
+        assert_eq!(bh.name, "CX-TEST");
+        assert_eq!(bh.version, Some((3, 1, 4024)));
+        assert!(bh.typ == BhType::CX3);
+        // found via the interface on the same subnet
+        assert_eq!(bh.if_addr, IF_ADDR);
+        assert!(bh.rtt.is_some());
+        // both probes went to the scanned address
+        let sent: Vec<_> = sock.sent.borrow().iter().map(|(_, addr)| *addr).collect();
+        assert_eq!(sent, [bc_addr(BH_ADDR), cx_addr(BH_ADDR)]);
+    }
+
+    #[test]
+    fn twincat2_reply_is_cx2() {
+        let sock = FakeSocket::new(vec![
+            (cx_reply("CX-OLD", [2, 11, 0, 8]), cx_addr(BH_ADDR)),
+        ]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], false);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].typ == BhType::CX2);
+    }
+
+    #[test]
+    fn bc_reply_is_parsed() {
+        let sock = FakeSocket::new(vec![(bc_reply("BC9120"), bc_addr(BH_ADDR))]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], false);
+        assert_eq!(found.len(), 1);
+        let bh = &found[0];
+        assert_eq!(bh.netid, BH_NETID);
+        assert_eq!(bh.name, "BC9120");
+        assert_eq!(bh.version, None);
+        assert!(bh.typ == BhType::BC);
+        assert_eq!(bh.if_addr, IF_ADDR);
+    }
+
+    #[test]
+    fn single_reply_stops_after_first() {
+        let other = Ipv4Addr::new(10, 0, 0, 6);
+        let sock = FakeSocket::new(vec![
+            (cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]), cx_addr(BH_ADDR)),
+            (cx_reply("CX-OTHER", [3, 1, 0xb8, 0x0f]), cx_addr(other)),
+        ]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], true);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].bh_addr, BH_ADDR);
+        // the second reply was never read
+        assert_eq!(sock.replies.borrow().len(), 1);
+    }
+
+    #[test]
+    fn short_packets_are_skipped() {
+        let mut short_bc = bc_reply("BC9120");
+        short_bc.pop();
+        let sock = FakeSocket::new(vec![
+            (vec![1, 2, 3], cx_addr(BH_ADDR)),
+            (vec![1, 2, 3], bc_addr(BH_ADDR)),
+            (short_bc, bc_addr(BH_ADDR)),
+            (cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]), cx_addr(BH_ADDR)),
+        ]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], false);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "CX-TEST");
+    }
+
+    #[test]
+    fn ipv6_reply_is_rejected() {
+        let v6_addr: SocketAddr = (Ipv6Addr::LOCALHOST, BECKHOFF_UDP_PORT).into();
+        let sock = FakeSocket::new(vec![(cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]), v6_addr)]);
+        assert!(scan(&scanner(), &sock, &[BH_ADDR], false).is_empty());
+    }
+
+    #[test]
+    fn truncated_identify_reply_is_skipped() {
+        let reply = cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]);
+        // cut off anywhere, from inside the header to the last version byte
+        for len in 0..reply.len() {
+            let sock = FakeSocket::new(vec![(reply[..len].to_vec(), cx_addr(BH_ADDR))]);
+            assert!(scan(&scanner(), &sock, &[BH_ADDR], false).is_empty(), "length {len}");
+        }
+    }
+
+    #[test]
+    fn duplicate_reply_is_reported_once() {
+        let reply = cx_reply("CX-TEST", [3, 1, 0xb8, 0x0f]);
+        let sock = FakeSocket::new(vec![
+            (reply.clone(), cx_addr(BH_ADDR)),
+            (reply, cx_addr(BH_ADDR)),
+        ]);
+        let found = scan(&scanner(), &sock, &[BH_ADDR], false);
+        assert_eq!(found.len(), 1);
+        // both replies were read
+        assert!(sock.replies.borrow().is_empty());
+    }
+
+    #[test]
+    fn malformed_reply_is_parse_error() {
+        let scanner = scanner();
+        let result = scanner.parse_reply(&[1, 2, 3], cx_addr(BH_ADDR), None);
+        assert!(matches!(result, Err(ScanError::Parse(addr, _)) if addr == cx_addr(BH_ADDR)));
+        let result = scanner.parse_reply(&[1, 2, 3], bc_addr(BH_ADDR), None);
+        assert!(matches!(result, Err(ScanError::Parse(..))));
+        // ignored replies are not errors
+        let v6_addr: SocketAddr = (Ipv6Addr::LOCALHOST, BECKHOFF_UDP_PORT).into();
+        assert!(matches!(scanner.parse_reply(&[1, 2, 3], v6_addr, None), Ok(None)));
+    }
+
+    #[test]
+    fn duplicate_netids_are_found() {
+        let other = AmsNetId::new(5, 1, 2, 4, 1, 1);
+        let seen = [(BH_NETID, BH_ADDR), (other, Ipv4Addr::new(10, 0, 0, 6)),
+                    (BH_NETID, Ipv4Addr::new(10, 0, 0, 7))];
+        assert_eq!(check_duplicate_netids(&seen), [BH_NETID]);
+        assert!(check_duplicate_netids(&seen[..2]).is_empty());
+    }
+
+    #[test]
+    fn large_subnet_is_rejected() {
+        let scanner = scanner();
+        for mask in [Ipv4Addr::UNSPECIFIED, Ipv4Addr::new(255, 254, 0, 0)] {
+            let result = scanner.scan_inner(Scan::Subnet(BH_ADDR, mask), &mut |_| (),
+                                            &mut |_, _| ());
+            assert!(matches!(result, Err(ScanError::SubnetTooLarge(_))));
+        }
+    }
+
+    #[test]
+    fn constructors_set_timeout() {
+        assert_eq!(Scanner::new(false).timeout, DEFAULT_SCAN_TIMEOUT);
+        assert_eq!(Scanner::with_timeout(false, Duration::ZERO).timeout, Duration::from_millis(1));
+        assert_eq!(Scanner::with_timeout(true, Duration::from_secs(2)).timeout,
+                   Duration::from_secs(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "DSCP out of range")]
+    fn dscp_over_six_bits_panics() {
+        let _ = Scanner::new(false).dscp(Some(64));
     }
 }
@@ -0,0 +1,118 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::util::{AdsCommand, AdsMessage};
+
+
+/// Direction of a forwarded frame.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Direction {
+    ToBeckhoff,
+    FromBeckhoff,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::ToBeckhoff => "to_beckhoff",
+            Direction::FromBeckhoff => "from_beckhoff",
+        }
+    }
+}
+
+
+/// Statistics about the traffic passing through the TCP forwarder,
+/// updated by the distributor.
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    /// Number of frames per direction.
+    pub frames: BTreeMap<Direction, u64>,
+    /// Number of bytes (including the AMS/TCP header) per direction.
+    pub bytes: BTreeMap<Direction, u64>,
+    /// Number of frames per direction and command ID.
+    pub commands: BTreeMap<(Direction, u16), u64>,
+    /// Number of replies from the Beckhoff per nonzero ADS error code.
+    pub errors: BTreeMap<u32, u64>,
+    /// Number of client frames delayed by the rate limit.
+    pub throttled: u64,
+    /// Number of notifications dropped by coalescing.
+    pub notifications_dropped: u64,
+    /// Number of currently connected clients.
+    pub active_connections: usize,
+    /// Number of times the connection to the Beckhoff was reestablished.
+    pub reconnects: u64,
+}
+
+impl Stats {
+    /// Count a frame passing in the given direction.
+    pub fn record(&mut self, dir: Direction, msg: &AdsMessage) {
+        *self.frames.entry(dir).or_default() += 1;
+        *self.bytes.entry(dir).or_default() += msg.0.len() as u64;
+        *self.commands.entry((dir, msg.get_cmd())).or_default() += 1;
+        if dir == Direction::FromBeckhoff {
+            match msg.get_result() {
+                Some(0) | None => {}
+                Some(err) => *self.errors.entry(err).or_default() += 1,
+            }
+        }
+    }
+
+    /// Return a copy of the current counters.
+    pub fn snapshot(&self) -> Stats {
+        self.clone()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} active connections", self.active_connections)?;
+        for dir in [Direction::ToBeckhoff, Direction::FromBeckhoff] {
+            write!(f, "; {}: {} frames, {} bytes", dir.name(),
+                   self.frames.get(&dir).copied().unwrap_or(0),
+                   self.bytes.get(&dir).copied().unwrap_or(0))?;
+            for (&(d, cmd), count) in &self.commands {
+                if d == dir {
+                    match AdsCommand::from(cmd) {
+                        AdsCommand::Unknown(id) => write!(f, ", cmd {id} {count}")?,
+                        command => write!(f, ", {} {count}", command.name())?,
+                    }
+                }
+            }
+        }
+        for (code, count) in &self.errors {
+            write!(f, "; error {code:#x}: {count}")?;
+        }
+        if self.throttled > 0 {
+            write!(f, "; {} frames throttled", self.throttled)?;
+        }
+        if self.notifications_dropped > 0 {
+            write!(f, "; {} notifications coalesced away", self.notifications_dropped)?;
+        }
+        if self.reconnects > 0 {
+            write!(f, "; {} reconnects to the Beckhoff", self.reconnects)?;
+        }
+        Ok(())
+    }
+}
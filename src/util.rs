@@ -20,13 +20,15 @@
 //
 // *****************************************************************************
 
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::io::Write;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
 
-use ads::AmsNetId;
+use ads::{AmsAddr, AmsNetId};
 use byteorder::{ByteOrder, LittleEndian as LE, WriteBytesExt};
 use log::debug;
-use mlzutil::bytes::hexdump;
 
 pub const BECKHOFF_BC_UDP_PORT: u16 = 48847; // 0xBECF
 pub const BECKHOFF_TCP_PORT:    u16 = ads::PORT; // 0xBF02
@@ -45,6 +47,199 @@ pub const DELNOTIF:     u16 = 7;
 pub const NOTIF:        u16 = 8;
 pub const READWRITE:    u16 = 9;
 
+/// The ADS command of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdsCommand {
+    ReadDeviceInfo,
+    Read,
+    Write,
+    ReadState,
+    WriteControl,
+    AddNotification,
+    DeleteNotification,
+    Notification,
+    ReadWrite,
+    Unknown(u16),
+}
+
+impl From<u16> for AdsCommand {
+    fn from(cmd: u16) -> Self {
+        match cmd {
+            DEVINFO => AdsCommand::ReadDeviceInfo,
+            READ => AdsCommand::Read,
+            WRITE => AdsCommand::Write,
+            GETSTATE => AdsCommand::ReadState,
+            WRITECONTROL => AdsCommand::WriteControl,
+            ADDNOTIF => AdsCommand::AddNotification,
+            DELNOTIF => AdsCommand::DeleteNotification,
+            NOTIF => AdsCommand::Notification,
+            READWRITE => AdsCommand::ReadWrite,
+            other => AdsCommand::Unknown(other),
+        }
+    }
+}
+
+impl AdsCommand {
+    /// Return a human readable name for the command.
+    pub fn name(self) -> &'static str {
+        match self {
+            AdsCommand::ReadDeviceInfo => "DevInfo",
+            AdsCommand::Read => "Read",
+            AdsCommand::Write => "Write",
+            AdsCommand::ReadState => "GetState",
+            AdsCommand::WriteControl => "WriteControl",
+            AdsCommand::AddNotification => "AddNotif",
+            AdsCommand::DeleteNotification => "DelNotif",
+            AdsCommand::Notification => "Notification",
+            AdsCommand::ReadWrite => "ReadWrite",
+            AdsCommand::Unknown(_) => "???",
+        }
+    }
+
+    /// Return the name of the enum variant, e.g. `ReadDeviceInfo`.
+    pub fn variant_name(self) -> &'static str {
+        match self {
+            AdsCommand::ReadDeviceInfo => "ReadDeviceInfo",
+            AdsCommand::Read => "Read",
+            AdsCommand::Write => "Write",
+            AdsCommand::ReadState => "ReadState",
+            AdsCommand::WriteControl => "WriteControl",
+            AdsCommand::AddNotification => "AddNotification",
+            AdsCommand::DeleteNotification => "DeleteNotification",
+            AdsCommand::Notification => "Notification",
+            AdsCommand::ReadWrite => "ReadWrite",
+            AdsCommand::Unknown(_) => "Unknown",
+        }
+    }
+}
+
+impl std::str::FromStr for AdsCommand {
+    type Err = String;
+
+    /// Parse a variant name, a short name as returned by `name`, or a
+    /// numeric command ID.  Names are case-insensitive.
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Ok(id) = s.parse::<u16>() {
+            return Ok(id.into());
+        }
+        (1..=READWRITE).map(AdsCommand::from)
+                       .find(|cmd| cmd.variant_name().eq_ignore_ascii_case(s) ||
+                                   cmd.name().eq_ignore_ascii_case(s))
+                       .ok_or_else(|| format!("unknown ADS command {s:?}"))
+    }
+}
+
+/// Decides which client requests are passed on to the Beckhoff.
+pub enum CommandFilter {
+    All,
+    Allow(Vec<AdsCommand>),
+    Deny(Vec<AdsCommand>),
+}
+
+impl CommandFilter {
+    pub fn allows(&self, cmd: AdsCommand) -> bool {
+        match self {
+            CommandFilter::All => true,
+            CommandFilter::Allow(cmds) => cmds.contains(&cmd),
+            CommandFilter::Deny(cmds) => !cmds.contains(&cmd),
+        }
+    }
+}
+
+impl From<AdsCommand> for u16 {
+    fn from(cmd: AdsCommand) -> Self {
+        match cmd {
+            AdsCommand::ReadDeviceInfo => DEVINFO,
+            AdsCommand::Read => READ,
+            AdsCommand::Write => WRITE,
+            AdsCommand::ReadState => GETSTATE,
+            AdsCommand::WriteControl => WRITECONTROL,
+            AdsCommand::AddNotification => ADDNOTIF,
+            AdsCommand::DeleteNotification => DELNOTIF,
+            AdsCommand::Notification => NOTIF,
+            AdsCommand::ReadWrite => READWRITE,
+            AdsCommand::Unknown(other) => other,
+        }
+    }
+}
+
+/// Rewrites NetIDs between what clients use and what the devices have.
+///
+/// Both directions are kept in sync by `register`, so that replies are
+/// always translated back to the NetID the request was sent to.
+#[derive(Default)]
+pub struct NetIdMap {
+    outgoing: HashMap<AmsNetId, AmsNetId>,
+    incoming: HashMap<AmsNetId, AmsNetId>,
+}
+
+impl NetIdMap {
+    /// Let clients address the device `device_id` as `client_id`.
+    pub fn register(&mut self, client_id: AmsNetId, device_id: AmsNetId) {
+        if let Some(old) = self.outgoing.insert(client_id, device_id) {
+            self.incoming.remove(&old);
+        }
+        if let Some(old) = self.incoming.insert(device_id, client_id) {
+            self.outgoing.remove(&old);
+        }
+    }
+
+    /// Translate a NetID used by a client into the device's NetID.
+    pub fn translate_outgoing(&self, id: AmsNetId) -> Option<AmsNetId> {
+        self.outgoing.get(&id).copied()
+    }
+
+    /// Translate a device's NetID into the one the clients use.
+    pub fn translate_incoming(&self, id: AmsNetId) -> Option<AmsNetId> {
+        self.incoming.get(&id).copied()
+    }
+}
+
+/// Parse a `CLIENT=DEVICE` pair of NetIDs.
+pub fn parse_netid_alias(s: &str) -> Result<(AmsNetId, AmsNetId), String> {
+    let (client, device) = s.split_once('=').ok_or("expected CLIENT=DEVICE")?;
+    let parse = |id: &str| id.trim().parse::<AmsNetId>()
+                             .map_err(|_| format!("invalid NetID {id:?}"));
+    Ok((parse(client)?, parse(device)?))
+}
+
+/// Parse a rate limit, which must be at least 1 per second.
+pub fn parse_rate(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("rate limit must be at least 1".into()),
+        Ok(rate) => Ok(rate),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parse the TCP keepalive probe interval, which must be at least 1 second.
+pub fn parse_keepalive_interval(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("keepalive interval must be at least 1".into()),
+        Ok(secs) => Ok(secs),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parse a DSCP value, which only has six bits.
+pub fn parse_dscp(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(dscp) if dscp > 63 => Err("DSCP must be between 0 and 63".into()),
+        Ok(dscp) => Ok(dscp),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parse the scan reply buffer size; below 64 bytes even short CX replies
+/// would be truncated.
+pub fn parse_scan_bufsize(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(size) if size < 64 => Err("buffer size must be at least 64".into()),
+        Ok(size) => Ok(size),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 #[derive(Debug)]
 pub enum InOutClientBH {
     InFrmClnt,
@@ -53,9 +248,120 @@ pub enum InOutClientBH {
     OutToBeck,
 }
 
+/// Default limit for the length of a message read from a socket.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 16 << 20;
+
+/// Length of the AMS/TCP prefix and the AMS header of each message.
+pub const HEADER_LEN: usize = 6 + 32;
+
 /// Represents an ADS message.
 pub struct AdsMessage(pub Vec<u8>);
 
+/// Reasons why a byte buffer is not a valid ADS message.
+#[derive(Debug)]
+pub enum AdsError {
+    TooShort { have: usize, need: usize },
+    LengthMismatch { declared: usize, actual: usize },
+    BadMagic { reserved: u16 },
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for AdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdsError::TooShort { have, need } =>
+                write!(f, "message too short: {have} bytes, need {need}"),
+            AdsError::LengthMismatch { declared, actual } =>
+                write!(f, "message length mismatch: {declared} bytes declared, {actual} present"),
+            AdsError::BadMagic { reserved } =>
+                write!(f, "invalid AMS/TCP header: reserved field is {reserved:#x}"),
+            AdsError::TooLarge { size, max } =>
+                write!(f, "message too large: {size} bytes, limit is {max}"),
+        }
+    }
+}
+
+impl std::error::Error for AdsError {}
+
+impl fmt::Debug for AdsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() < HEADER_LEN {
+            return write!(f, "AdsMessage(truncated, {} bytes)", self.0.len());
+        }
+        write!(f, "{} {}:{}->{}:{} {} flags={:#x} invoke_id={:#x} len={}",
+               if self.is_reply() { "Rep" } else { "Req" },
+               self.get_source_id(), self.get_source_port(),
+               self.get_dest_id(), self.get_dest_port(),
+               self.get_cmd_name(), self.get_state_flags(), self.get_invoke_id(),
+               self.get_data().len())?;
+        if let Some(err) = self.get_result().filter(|&err| err != 0) {
+            write!(f, " error={err:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for an ADS message; the length fields are filled in by `build`.
+pub struct AdsMessageBuilder {
+    dest: AmsAddr,
+    source: AmsAddr,
+    cmd: u16,
+    state_flags: u16,
+    invoke_id: u32,
+    data: Vec<u8>,
+}
+
+impl AdsMessageBuilder {
+    /// Start a TCP request with the given command and zero addresses.
+    pub fn new(cmd: u16) -> Self {
+        let zero = AmsAddr::new(AmsNetId([0; 6]), 0);
+        Self { dest: zero, source: zero, cmd, state_flags: 4, invoke_id: 0, data: Vec::new() }
+    }
+
+    pub fn dest(mut self, dest: AmsAddr) -> Self {
+        self.dest = dest;
+        self
+    }
+
+    pub fn source(mut self, source: AmsAddr) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn state_flags(mut self, state_flags: u16) -> Self {
+        self.state_flags = state_flags;
+        self
+    }
+
+    pub fn invoke_id(mut self, invoke_id: u32) -> Self {
+        self.invoke_id = invoke_id;
+        self
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.data = data.to_vec();
+        self
+    }
+
+    /// Assemble the AMS/TCP prefix, AMS header and payload.
+    pub fn build(self) -> AdsMessage {
+        let mut v = Vec::with_capacity(HEADER_LEN + self.data.len());
+        v.write_u16::<LE>(0).unwrap();
+        v.write_u32::<LE>(32 + self.data.len() as u32).unwrap();
+        v.write_all(&self.dest.netid().0).unwrap();
+        v.write_u16::<LE>(self.dest.port()).unwrap();
+        v.write_all(&self.source.netid().0).unwrap();
+        v.write_u16::<LE>(self.source.port()).unwrap();
+        v.write_u16::<LE>(self.cmd).unwrap();
+        v.write_u16::<LE>(self.state_flags).unwrap();
+        v.write_u32::<LE>(self.data.len() as u32).unwrap();
+        v.write_u32::<LE>(0).unwrap(); // Error-code
+        v.write_u32::<LE>(self.invoke_id).unwrap();
+        v.write_all(&self.data).unwrap();
+        AdsMessage(v)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 #[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct AddNotifReqData {
@@ -70,35 +376,60 @@ pub struct AddNotifReqData {
 }
 
 impl AdsMessage {
-    pub fn from_bytes(msg: Vec<u8>) -> AdsMessage {
+    /// Create a message from bytes, checking that they contain at least the
+    /// full header, that the reserved prefix is zero and that the length
+    /// field matches.
+    pub fn try_new(msg: Vec<u8>) -> Result<AdsMessage, AdsError> {
+        if msg.len() < HEADER_LEN {
+            return Err(AdsError::TooShort { have: msg.len(), need: HEADER_LEN });
+        }
+        let reserved = LE::read_u16(&msg[0..2]);
+        if reserved != 0 {
+            return Err(AdsError::BadMagic { reserved });
+        }
         let msg = AdsMessage(msg);
-        // todo: could expand checks here
-        assert!(msg.get_length() == msg.0.len());
-        msg
+        if msg.get_length() != msg.0.len() {
+            return Err(AdsError::LengthMismatch { declared: msg.get_length(), actual: msg.0.len() });
+        }
+        Ok(msg)
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(dstid: AmsNetId, dstport: u16, srcid: AmsNetId, srcport: u16,
-               cmd: u16, reply: bool, invoke_id: u32, data: &[u8]) -> AdsMessage {
-        let mut v = vec![0; 2];
-        v.write_u32::<LE>(32 + data.len() as u32).unwrap();
-        v.write_all(&dstid.0).unwrap();
-        v.write_u16::<LE>(dstport).unwrap();
-        v.write_all(&srcid.0).unwrap();
-        v.write_u16::<LE>(srcport).unwrap();
-        v.write_u16::<LE>(cmd).unwrap();
-        v.write_u16::<LE>(4 | (reply as u16)).unwrap();
-        v.write_u32::<LE>(data.len() as u32).unwrap();
-        v.write_u32::<LE>(0).unwrap(); // Error-code
-        v.write_u32::<LE>(invoke_id).unwrap();
-        v.write_all(data).unwrap();
-        AdsMessage(v)
+    /// Return the length of the message following the 6-byte AMS/TCP
+    /// prefix, rejecting lengths above `max` before anything is allocated.
+    pub fn body_len(prefix: &[u8], max: usize) -> Result<usize, AdsError> {
+        if prefix.len() < 6 {
+            return Err(AdsError::TooShort { have: prefix.len(), need: 6 });
+        }
+        let size = LE::read_u32(&prefix[2..6]) as usize;
+        if size > max {
+            return Err(AdsError::TooLarge { size, max });
+        }
+        Ok(size)
+    }
+
+    pub fn new(dest: AmsAddr, source: AmsAddr, cmd: u16, reply: bool, invoke_id: u32,
+               data: &[u8]) -> AdsMessage {
+        AdsMessageBuilder::new(cmd)
+            .dest(dest)
+            .source(source)
+            .state_flags(4 | (reply as u16))
+            .invoke_id(invoke_id)
+            .data(data)
+            .build()
     }
 
     pub fn get_length(&self) -> usize {
         6 + LE::read_u32(&self.0[2..6]) as usize
     }
 
+    pub fn get_dest(&self) -> AmsAddr {
+        AmsAddr::new(self.get_dest_id(), self.get_dest_port())
+    }
+
+    pub fn get_source(&self) -> AmsAddr {
+        AmsAddr::new(self.get_source_id(), self.get_source_port())
+    }
+
     pub fn get_dest_id(&self) -> AmsNetId {
         AmsNetId::from_slice(&self.0[6..12]).unwrap()
     }
@@ -119,10 +450,24 @@ impl AdsMessage {
         LE::read_u16(&self.0[22..24])
     }
 
+    pub fn get_command(&self) -> AdsCommand {
+        self.get_cmd().into()
+    }
+
+    /// Return a human readable name for the command.
+    pub fn get_cmd_name(&self) -> &'static str {
+        self.get_command().name()
+    }
+
     pub fn get_state_flags(&self) -> u16 {
         LE::read_u16(&self.0[24..26])
     }
 
+    /// Return true if the response bit is set in the state flags.
+    pub fn is_reply(&self) -> bool {
+        self.get_state_flags() & 1 != 0
+    }
+
     pub fn get_error_code(&self) -> u32 {
         LE::read_u32(&self.0[30..34]) as u32
     }
@@ -131,14 +476,43 @@ impl AdsMessage {
         LE::read_u32(&self.0[34..38]) as u32
     }
 
+    /// Return the ADS data following the header (empty if there is none).
+    pub fn get_data(&self) -> &[u8] {
+        self.0.get(HEADER_LEN..).unwrap_or(&[])
+    }
+
+    /// Return the ADS result of a reply, or None for requests.
+    ///
+    /// A non-zero error code in the AMS header takes precedence over the
+    /// result field at the start of the reply payload.
+    pub fn get_result(&self) -> Option<u32> {
+        if !self.is_reply() || self.get_cmd() == NOTIF {
+            return None;
+        }
+        match self.get_error_code() {
+            0 if self.get_data().len() >= 4 => Some(LE::read_u32(self.get_data())),
+            err => Some(err),
+        }
+    }
+
     pub fn get_add_notification_reply_handle(&self) -> Option<u32> {
-        if self.0.len() >= 45 && self.get_cmd() == ADDNOTIF && LE::read_u32(&self.0[38..]) == 0 {
+        if self.0.len() >= 46 && self.get_cmd() == ADDNOTIF && LE::read_u32(&self.0[38..]) == 0 {
             Some::<u32>(LE::read_u32(&self.0[42..]))
         } else {
             None
         }
     }
 
+    pub fn patch_dest(&mut self, addr: &AmsAddr) {
+        self.patch_dest_id(addr.netid());
+        self.patch_dest_port(addr.port());
+    }
+
+    pub fn patch_source(&mut self, addr: &AmsAddr) {
+        self.patch_source_id(addr.netid());
+        self.patch_source_port(addr.port());
+    }
+
     pub fn patch_dest_id(&mut self, id: AmsNetId) {
         self.0[6..12].copy_from_slice(&id.0);
     }
@@ -158,33 +532,36 @@ impl AdsMessage {
         LE::write_u32(&mut self.0[34..38], invoke_id)
     }
 
+    /// Return a one-line summary of the message: addresses, command,
+    /// invoke ID, payload length and, for replies, the error code.
+    pub fn summary_line(&self) -> String {
+        let mut line = format!("{}:{} -> {}:{} {} {} invoke_id={:#x} len={}",
+                               self.get_source_id(), self.get_source_port(),
+                               self.get_dest_id(), self.get_dest_port(),
+                               self.get_cmd_name(), if self.is_reply() { "Rep" } else { "Req" },
+                               self.get_invoke_id(), self.get_data().len());
+        if self.is_reply() {
+            if let Some(err) = self.get_result() {
+                line.push_str(&format!(" error={err:#x}"));
+            }
+        }
+        line
+    }
+
     /// Print a summary of the request/response to stdout.
     pub fn summarize(&self, in_out_bh_clnt: InOutClientBH, do_hex: bool) {
         let mut debug_printed = false;
         let dport = self.get_dest_port();
-        let stf = self.get_state_flags();
-        let mut err = self.get_error_code();
         let inv = self.get_invoke_id();
         let cmd = self.get_cmd();
-        let cmdname = match cmd {
-            DEVINFO => "DevInfo",
-            READ => "Read ",
-            WRITE => "Write",
-            GETSTATE => "GetState",
-            WRITECONTROL => "WriteControl",
-            ADDNOTIF => "AddNotif",
-            DELNOTIF => "DelNotif",
-            NOTIF => "Notification",
-            READWRITE => "ReadWrite",
-            _ => "???",
-        };
-        let reply = stf & 1 != 0;
+        let cmdname = self.get_cmd_name();
+        let reply = self.is_reply();
 
         let prefix = format!("{in_out_bh_clnt:?} {} {}:{}[{inv:#08x}]->{}:{}  {cmdname}",
                              if reply { "Rep" } else { "Req" },
                              self.get_source_id(), self.get_source_port(),
                              self.get_dest_id(), self.get_dest_port());
-        if cmd == NOTIF && self.0.len() >= 45 {
+        if cmd == NOTIF && self.0.len() >= 58 {
             let pay_len = LE::read_u32(&self.0[26..]);
             let error = LE::read_u32(&self.0[30..]);
             let invoke_id = LE::read_u32(&self.0[34..]);
@@ -200,12 +577,22 @@ impl AdsMessage {
             let mut read_index = 58;
             // Hand-made loop
             while sample_idx < num_samples {
-                let handle = LE::read_u32(&self.0[read_index..]);
-                let sample_size = LE::read_u32(&self.0[read_index+4..]);
-                let sample_data = &self.0[read_index+8..read_index+8+sample_size as usize];
+                // the counts come from the wire, so don't read past the end
+                let sample = self.0.get(read_index..read_index+8).and_then(|head| {
+                    let size = LE::read_u32(&head[4..]) as usize;
+                    Some((LE::read_u32(head), size,
+                          self.0.get(read_index+8..read_index+8+size)?))
+                });
+                let (handle, sample_size, sample_data) = match sample {
+                    Some(sample) => sample,
+                    None => {
+                        debug!("{prefix}: sample_idx={sample_idx} is truncated");
+                        break;
+                    }
+                };
                 debug!("{prefix}: sample_idx={sample_idx} handle={handle} read_index={read_index} \
                         sample_size={sample_size} sample_data={sample_data:?}");
-                read_index += 8 + sample_size as usize;
+                read_index += 8 + sample_size;
                 sample_idx += 1;
             }
         }
@@ -219,7 +606,7 @@ impl AdsMessage {
                     debug!("{prefix}: {igrp:#x}:{ioff:#x} {len} bytes");
                     debug_printed = true;
                 }
-                ADDNOTIF => if self.0.len() >= 61 {
+                ADDNOTIF => if self.0.len() >= 62 {
                     let invoke_id = LE::read_u32(&self.0[34..]);
                     let igrp = LE::read_u32(&self.0[38..]);
                     let ioff = LE::read_u32(&self.0[42..]);
@@ -249,7 +636,7 @@ impl AdsMessage {
             }
         } else {
             match cmd {
-                ADDNOTIF => if self.0.len() >= 45 {
+                ADDNOTIF => if self.0.len() >= 46 {
                     let invoke_id = LE::read_u32(&self.0[34..]);
                     let result = LE::read_u32(&self.0[38..]);
                     let handle = LE::read_u32(&self.0[42..]);
@@ -259,12 +646,9 @@ impl AdsMessage {
                 NOTIF => {}
                 _ => {
                     let invoke_id = LE::read_u32(&self.0[34..]);
-                    if err == 0 && self.0.len() >= 42 && LE::read_u32(&self.0[38..]) != 0 {
-                        err = LE::read_u32(&self.0[38..]);
-                    }
+                    let err = self.get_result().unwrap_or(0);
                     if err != 0 {
-                        let msg: ads::Result<()> = ads::errors::ads_error("ERROR", err);
-                        debug!("{prefix}: {} invoke_id={invoke_id}\n", msg.unwrap_err());
+                        debug!("{prefix}: {} invoke_id={invoke_id}\n", ads_error_str(err));
                     } else {
                         debug!("{prefix}: no error invoke_id={invoke_id}\n");
                     }
@@ -303,6 +687,154 @@ impl AdsMessage {
 }
 
 
+/// Write a hexdump of the data, 16 bytes per line with offset and ASCII.
+pub fn hexdump_to(w: &mut dyn io::Write, data: &[u8]) -> io::Result<()> {
+    hexdump_width_to(w, data, 16)
+}
+
+/// Write a hexdump of the data with `width` bytes per line.
+pub fn hexdump_width_to(w: &mut dyn io::Write, data: &[u8], width: usize) -> io::Result<()> {
+    write_hexdump(w, data, width, 0, false)
+}
+
+/// Write a hexdump whose address column starts at `start_offset`,
+/// optionally with ANSI colors.
+fn write_hexdump(w: &mut dyn io::Write, data: &[u8], width: usize,
+                 start_offset: usize, color: bool) -> io::Result<()> {
+    let width = width.max(1);
+    // three characters per byte, plus an extra space between groups of eight
+    let hex_width = 3 * width + (width - 1) / 8;
+    for (i, line) in data.chunks(width).enumerate() {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        let mut visible = 0;
+        for (j, &b) in line.iter().enumerate() {
+            if j > 0 && j % 8 == 0 {
+                hex.push(' ');
+                visible += 1;
+            }
+            visible += 3;
+            match color_of(b).filter(|_| color) {
+                Some(esc) => {
+                    hex.push_str(&format!("{esc}{b:02x}\x1b[0m "));
+                    ascii.push_str(&format!("{esc}{}\x1b[0m", printable(b)));
+                }
+                None => {
+                    hex.push_str(&format!("{b:02x} "));
+                    ascii.push(printable(b));
+                }
+            }
+        }
+        let pad = hex_width - visible;
+        writeln!(w, "{:04x}: {hex}{:pad$} {ascii}", start_offset + i * width, "")?;
+    }
+    Ok(())
+}
+
+/// Return the ANSI color used for a byte in a colored hexdump.
+fn color_of(b: u8) -> Option<&'static str> {
+    match b {
+        0 => Some("\x1b[2m"),
+        32..=126 => Some("\x1b[32m"),
+        128..=255 => Some("\x1b[33m"),
+        _ => None,
+    }
+}
+
+/// Return the character shown for a byte in the ASCII column of a hexdump.
+/// Only 0x20 to 0x7e are shown; DEL (0x7f) and everything above is a dot.
+fn printable(b: u8) -> char {
+    if (32..=126).contains(&b) { b as char } else { '.' }
+}
+
+/// Format a hexdump of the data into a string.
+pub fn hexdump_to_string(data: &[u8]) -> String {
+    let mut out = Vec::new();
+    hexdump_to(&mut out, data).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("hexdump is ASCII")
+}
+
+/// Print a hexdump of the data to stdout.
+pub fn hexdump(data: &[u8]) {
+    hexdump_at(data, 0);
+}
+
+/// Print a hexdump of a slice of a larger buffer, so that the addresses
+/// match the position of the slice within the buffer.
+pub fn hexdump_at(data: &[u8], start_offset: usize) {
+    let _ = write_hexdump(&mut io::stdout().lock(), data, 16, start_offset, false);
+}
+
+/// Print a hexdump to stdout, colored if stdout is a terminal and the
+/// NO_COLOR environment variable is not set.
+pub fn hexdump_colored(data: &[u8]) {
+    hexdump_colored_at(data, 0);
+}
+
+/// Like `hexdump_at`, but colored like `hexdump_colored`.
+pub fn hexdump_colored_at(data: &[u8], start_offset: usize) {
+    let color = atty::is(atty::Stream::Stdout) && std::env::var_os("NO_COLOR").is_none();
+    let _ = write_hexdump(&mut io::stdout().lock(), data, 16, start_offset, color);
+}
+
+/// Print a hexdump of the data to stdout with `width` bytes per line.
+pub fn hexdump_width(data: &[u8], width: usize) {
+    let _ = hexdump_width_to(&mut io::stdout().lock(), data, width);
+}
+
+
+/// Parse a subnet given as `a.b.c.d/nn` into its address and netmask.
+pub fn parse_cidr(s: &str) -> Result<(Ipv4Addr, Ipv4Addr), &'static str> {
+    let (addr, prefix) = s.split_once('/').ok_or("missing prefix length")?;
+    let addr = addr.parse().map_err(|_| "invalid IPv4 address")?;
+    let prefix: u32 = prefix.parse().map_err(|_| "invalid prefix length")?;
+    if prefix > 32 {
+        return Err("prefix length must be between 0 and 32");
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Ok((addr, mask.into()))
+}
+
+
+/// Return the directed broadcast address of the subnet `addr` is in.
+///
+/// A /31 point-to-point link has no broadcast address, so the peer's
+/// address is returned instead.
+pub fn broadcast_addr(addr: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
+    if u32::from(mask).leading_ones() == 31 {
+        return (u32::from(addr) ^ 1).into();
+    }
+    (u32::from(addr) | !u32::from(mask)).into()
+}
+
+
+/// Return the network address of the subnet `addr` is in.
+pub fn network_addr(addr: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
+    (u32::from(addr) & u32::from(mask)).into()
+}
+
+
+/// Return the number of usable host addresses in a subnet with this mask.
+///
+/// Point-to-point /31 subnets have two usable hosts (RFC 3021), and a /32
+/// has just one.
+pub fn host_count(mask: Ipv4Addr) -> u32 {
+    match u32::from(mask).leading_ones() {
+        32 => 1,
+        31 => 2,
+        // all addresses but the network and broadcast ones
+        prefix => (u32::MAX >> prefix) - 1,
+    }
+}
+
+
+/// Return a description of an ADS error code, e.g. "target port not found".
+pub fn ads_error_str(code: u32) -> String {
+    let res: ads::Result<()> = ads::errors::ads_error("ERROR", code);
+    res.map_or_else(|e| e.to_string(), |_| "no error".into())
+}
+
+
 // https://infosys.beckhoff.com/english.php?content=../content/1033/tc3_ads_intro/115883019.html
 pub struct NotifData {
     data: Vec<u8>,
@@ -334,3 +866,275 @@ impl NotifData {
         &self.data
     }
 }
+
+impl Default for NotifData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_count_for_all_prefixes() {
+        for prefix in 0..=32 {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let expected = match prefix {
+                32 => 1,
+                31 => 2,
+                _ => (1u64 << (32 - prefix)) - 2,
+            };
+            assert_eq!(host_count(mask.into()) as u64, expected, "prefix {prefix}");
+        }
+    }
+
+    #[test]
+    fn network_addr_masks_host_bits() {
+        let addr = Ipv4Addr::new(10, 2, 3, 77);
+        assert_eq!(network_addr(addr, Ipv4Addr::new(255, 255, 255, 0)),
+                   Ipv4Addr::new(10, 2, 3, 0));
+        assert_eq!(network_addr(addr, Ipv4Addr::new(255, 255, 255, 254)),
+                   Ipv4Addr::new(10, 2, 3, 76));
+        assert_eq!(network_addr(addr, Ipv4Addr::BROADCAST), addr);
+        assert_eq!(network_addr(addr, Ipv4Addr::UNSPECIFIED), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn parse_cidr_edge_cases() {
+        assert_eq!(parse_cidr("10.2.3.0/24"),
+                   Ok((Ipv4Addr::new(10, 2, 3, 0), Ipv4Addr::new(255, 255, 255, 0))));
+        assert_eq!(parse_cidr("0.0.0.0/0"), Ok((Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(parse_cidr("10.0.0.4/31"),
+                   Ok((Ipv4Addr::new(10, 0, 0, 4), Ipv4Addr::new(255, 255, 255, 254))));
+        assert_eq!(parse_cidr("10.0.0.4/32"),
+                   Ok((Ipv4Addr::new(10, 0, 0, 4), Ipv4Addr::BROADCAST)));
+        assert!(parse_cidr("10.0.0.4/33").is_err());
+        assert!(parse_cidr("10.0.0.4").is_err());
+        assert!(parse_cidr("10.0.0/24").is_err());
+        assert!(parse_cidr("10.0.0.4/x").is_err());
+    }
+
+    #[test]
+    fn broadcast_addr_per_prefix() {
+        let addr = Ipv4Addr::new(10, 2, 3, 4);
+        assert_eq!(broadcast_addr(addr, Ipv4Addr::new(255, 255, 255, 0)),
+                   Ipv4Addr::new(10, 2, 3, 255));
+        // point-to-point links: the peer
+        assert_eq!(broadcast_addr(addr, Ipv4Addr::new(255, 255, 255, 254)),
+                   Ipv4Addr::new(10, 2, 3, 5));
+        assert_eq!(broadcast_addr(Ipv4Addr::new(10, 2, 3, 5), Ipv4Addr::new(255, 255, 255, 254)),
+                   addr);
+        assert_eq!(broadcast_addr(addr, Ipv4Addr::BROADCAST), addr);
+    }
+
+    /// A minimal valid frame: prefix, AMS header and no data.
+    fn empty_frame() -> Vec<u8> {
+        let mut msg = vec![0; HEADER_LEN];
+        LE::write_u32(&mut msg[2..6], (HEADER_LEN - 6) as u32);
+        msg
+    }
+
+    #[test]
+    fn valid_frame_is_accepted() {
+        assert!(AdsMessage::try_new(empty_frame()).is_ok());
+    }
+
+    #[test]
+    fn short_frame_is_rejected() {
+        let mut msg = empty_frame();
+        msg.pop();
+        match AdsMessage::try_new(msg).err() {
+            Some(AdsError::TooShort { have, need }) => {
+                assert_eq!(have, HEADER_LEN - 1);
+                assert_eq!(need, HEADER_LEN);
+            }
+            other => panic!("expected TooShort, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn nonzero_reserved_is_rejected() {
+        let mut msg = empty_frame();
+        msg[0] = 1;
+        match AdsMessage::try_new(msg).err() {
+            Some(AdsError::BadMagic { reserved }) => assert_eq!(reserved, 1),
+            other => panic!("expected BadMagic, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let mut msg = empty_frame();
+        msg.push(0);
+        match AdsMessage::try_new(msg).err() {
+            Some(AdsError::LengthMismatch { declared, actual }) => {
+                assert_eq!(declared, HEADER_LEN);
+                assert_eq!(actual, HEADER_LEN + 1);
+            }
+            other => panic!("expected LengthMismatch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut prefix = [0; 6];
+        LE::write_u32(&mut prefix[2..6], 1000);
+        assert_eq!(AdsMessage::body_len(&prefix, 1000).unwrap(), 1000);
+        match AdsMessage::body_len(&prefix, 999) {
+            Err(AdsError::TooLarge { size, max }) => {
+                assert_eq!(size, 1000);
+                assert_eq!(max, 999);
+            }
+            other => panic!("expected TooLarge, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn hexdump_pads_partial_last_line() {
+        let mut out = Vec::new();
+        hexdump_width_to(&mut out, b"ABCDEFGHIJ", 8).unwrap();
+        let expected = format!("0000: 41 42 43 44 45 46 47 48  ABCDEFGH\n\
+                                0008: 49 4a {:18} IJ\n", "");
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        // the stdout variant goes through the same code
+        hexdump_width(b"ABCDEFGHIJ", 8);
+    }
+
+    #[test]
+    fn printable_boundaries() {
+        assert_eq!(printable(0x1f), '.');
+        assert_eq!(printable(0x20), ' ');
+        assert_eq!(printable(0x7e), '~');
+        assert_eq!(printable(0x7f), '.');
+        assert_eq!(printable(0x80), '.');
+    }
+
+    #[test]
+    fn command_id_round_trip() {
+        for id in 0..=u16::MAX {
+            assert_eq!(u16::from(AdsCommand::from(id)), id);
+        }
+        assert_eq!(AdsCommand::from(DEVINFO), AdsCommand::ReadDeviceInfo);
+        assert_eq!(AdsCommand::from(READWRITE), AdsCommand::ReadWrite);
+        assert_eq!(AdsCommand::from(0), AdsCommand::Unknown(0));
+        assert_eq!(AdsCommand::from(10), AdsCommand::Unknown(10));
+    }
+
+    #[test]
+    fn command_names_are_parsed() {
+        for id in 1..=READWRITE {
+            let cmd = AdsCommand::from(id);
+            assert_eq!(cmd.variant_name().parse::<AdsCommand>().unwrap(), cmd);
+            assert_eq!(cmd.name().parse::<AdsCommand>().unwrap(), cmd);
+            assert_eq!(id.to_string().parse::<AdsCommand>().unwrap(), cmd);
+        }
+        assert_eq!("readdeviceinfo".parse::<AdsCommand>().unwrap(), AdsCommand::ReadDeviceInfo);
+        assert_eq!("ADDNOTIFICATION".parse::<AdsCommand>().unwrap(), AdsCommand::AddNotification);
+        assert_eq!("getstate".parse::<AdsCommand>().unwrap(), AdsCommand::ReadState);
+        assert!("Bogus".parse::<AdsCommand>().is_err());
+    }
+
+    #[test]
+    fn summarize_survives_truncated_frames() {
+        let addr = AmsAddr::new(AmsNetId::new(1, 2, 3, 4, 1, 1), 851);
+        for cmd in [NOTIF, ADDNOTIF, DELNOTIF, READ, WRITE, READWRITE] {
+            for reply in [false, true] {
+                for len in 0..30 {
+                    let msg = AdsMessage::new(addr, addr, cmd, reply, 1, &vec![0xff; len]);
+                    msg.summarize(InOutClientBH::InFrmClnt, false);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_rate_is_rejected() {
+        assert_eq!(parse_rate("1"), Ok(1));
+        assert_eq!(parse_rate("4000000000"), Ok(4_000_000_000));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("").is_err());
+    }
+
+    #[test]
+    fn patch_dest_and_source() {
+        let old = AmsAddr::new(AmsNetId::new(1, 1, 1, 1, 1, 1), 0x0101);
+        let mut msg = AdsMessage::new(old, old, READ, false, 1, &[]);
+        let dest = AmsAddr::new(AmsNetId::new(5, 1, 2, 3, 1, 1), 851);
+        let source = AmsAddr::new(AmsNetId::new(10, 0, 0, 9, 1, 1), 30000);
+        msg.patch_dest(&dest);
+        assert_eq!(msg.0[6..14], [5, 1, 2, 3, 1, 1, 0x53, 0x03]);
+        assert_eq!(msg.get_source(), old);
+        msg.patch_source(&source);
+        assert_eq!(msg.0[14..22], [10, 0, 0, 9, 1, 1, 0x30, 0x75]);
+        assert_eq!(msg.get_dest(), dest);
+        assert_eq!(msg.get_source(), source);
+    }
+
+    #[test]
+    fn netid_string_round_trip() {
+        // a fixed xorshift sequence, so that failures are reproducible
+        let mut state = 0x2545_f491_u32;
+        for _ in 0..1000 {
+            let mut octets = [0; 6];
+            for octet in &mut octets {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *octet = state as u8;
+            }
+            let netid = AmsNetId(octets);
+            assert_eq!(netid.to_string().parse::<AmsNetId>().ok(), Some(netid));
+        }
+        for octets in [[0; 6], [255; 6]] {
+            let netid = AmsNetId(octets);
+            assert_eq!(netid.to_string().parse::<AmsNetId>().ok(), Some(netid));
+        }
+        // missing trailing octets default to 1
+        assert_eq!("5.18".parse::<AmsNetId>().ok(), Some(AmsNetId::new(5, 18, 1, 1, 1, 1)));
+        assert_eq!("5.18.2.3".parse::<AmsNetId>().ok(), Some(AmsNetId::new(5, 18, 2, 3, 1, 1)));
+        assert!("5.18.1.1.1.1.1".parse::<AmsNetId>().is_err());
+        assert!("5.256".parse::<AmsNetId>().is_err());
+    }
+
+    #[test]
+    fn netid_as_map_key() {
+        let mut routes = HashMap::new();
+        let bh = AmsNetId::new(5, 18, 1, 1, 1, 1);
+        routes.insert(bh, Ipv4Addr::new(192, 168, 1, 10));
+        routes.insert(AmsNetId::new(5, 18, 1, 2, 1, 1), Ipv4Addr::new(192, 168, 1, 11));
+        assert_eq!(routes.get(&bh), Some(&Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(routes.get(&"5.18".parse().unwrap()), Some(&Ipv4Addr::new(192, 168, 1, 10)));
+        assert_eq!(routes.get(&AmsNetId::new(5, 18, 1, 3, 1, 1)), None);
+    }
+
+    #[test]
+    fn zero_keepalive_interval_is_rejected() {
+        assert_eq!(parse_keepalive_interval("1"), Ok(1));
+        assert_eq!(parse_keepalive_interval("10"), Ok(10));
+        assert!(parse_keepalive_interval("0").is_err());
+        assert!(parse_keepalive_interval("x").is_err());
+    }
+
+    #[test]
+    fn dscp_is_six_bits() {
+        assert_eq!(parse_dscp("0"), Ok(0));
+        assert_eq!(parse_dscp("46"), Ok(46));
+        assert_eq!(parse_dscp("63"), Ok(63));
+        assert!(parse_dscp("64").is_err());
+        assert!(parse_dscp("255").is_err());
+        assert!(parse_dscp("-1").is_err());
+    }
+
+    #[test]
+    fn tiny_scan_bufsize_is_rejected() {
+        assert_eq!(parse_scan_bufsize("64"), Ok(64));
+        assert_eq!(parse_scan_bufsize("2048"), Ok(2048));
+        assert!(parse_scan_bufsize("63").is_err());
+        assert!(parse_scan_bufsize("0").is_err());
+        assert!(parse_scan_bufsize("big").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ads::AmsNetId;
+use byteorder::{ByteOrder, LittleEndian as LE, WriteBytesExt};
+
+use ads_forwarder::util::{AdsMessage, DEVINFO, GETSTATE, READ, WRITE};
+
+
+const UDP_MAGIC: u32 = 0x7114_6603;
+const UDP_IDENTIFY: u32 = 1;
+const UDP_ADDROUTE: u32 = 6;
+const UDP_REPLY: u32 = 0x8000_0000;
+const TAG_STATUS: u16 = 1;
+const TAG_TCVERSION: u16 = 3;
+const TAG_COMPUTERNAME: u16 = 5;
+
+const BC_SCAN_REQUEST: u32 = 1;
+const BC_SCAN_REPLY: u32 = 0x8000_0001;
+
+const ADSERR_SRVNOTSUPP: u32 = 0x701;
+const ADSERR_SYMBOLNOTFOUND: u32 = 0x710;
+
+/// Data of the mock's variables, by index group and offset.
+pub type Variables = Arc<Mutex<HashMap<(u32, u32), Vec<u8>>>>;
+
+
+/// A fake Beckhoff for tests without hardware.
+///
+/// Answers the CX identify or BC scan probe and AddRoute requests on UDP,
+/// and ReadState, ReadDeviceInfo, Read and Write requests on TCP.  Only
+/// variables given in `variables` can be read and written.
+pub struct MockBeckhoff {
+    pub netid: AmsNetId,
+    pub name: String,
+    pub version: (u8, u8, u16),
+    pub bc: bool,
+    pub state: u16,
+    pub variables: HashMap<(u32, u32), Vec<u8>>,
+}
+
+impl Default for MockBeckhoff {
+    fn default() -> Self {
+        MockBeckhoff { netid: AmsNetId::new(5, 1, 2, 3, 1, 1), name: "MOCK-CX".into(),
+                       version: (3, 1, 4024), bc: false, state: 5, variables: HashMap::new() }
+    }
+}
+
+/// The sockets of a running `MockBeckhoff`.
+pub struct RunningMock {
+    pub tcp_addr: SocketAddr,
+    pub udp_addr: SocketAddr,
+    pub bc_udp_addr: SocketAddr,
+    pub variables: Variables,
+    /// All TCP requests received so far
+    pub requests: Arc<Mutex<Vec<AdsMessage>>>,
+}
+
+impl MockBeckhoff {
+    /// Start serving on `ip`, with free ports picked by the system.
+    pub fn start(self, ip: Ipv4Addr) -> io::Result<RunningMock> {
+        self.start_on(ip, 0, 0, 0)
+    }
+
+    /// Start serving on `ip` and the given ports, e.g. the standard ones
+    /// for a forwarder to connect to.
+    pub fn start_on(self, ip: Ipv4Addr, bc_udp_port: u16, udp_port: u16, tcp_port: u16)
+                    -> io::Result<RunningMock> {
+        let tcp = TcpListener::bind((ip, tcp_port))?;
+        let udp = UdpSocket::bind((ip, udp_port))?;
+        let bc_udp = UdpSocket::bind((ip, bc_udp_port))?;
+        let mock = Arc::new(self);
+        let running = RunningMock {
+            tcp_addr: tcp.local_addr()?,
+            udp_addr: udp.local_addr()?,
+            bc_udp_addr: bc_udp.local_addr()?,
+            variables: Arc::new(Mutex::new(mock.variables.clone())),
+            requests: Arc::default(),
+        };
+
+        let m = mock.clone();
+        thread::spawn(move || m.serve_udp(udp));
+        let m = mock.clone();
+        thread::spawn(move || m.serve_bc_udp(bc_udp));
+        let (variables, requests) = (running.variables.clone(), running.requests.clone());
+        thread::spawn(move || for conn in tcp.incoming().flatten() {
+            let (m, variables, requests) = (mock.clone(), variables.clone(), requests.clone());
+            thread::spawn(move || m.serve_client(conn, &variables, &requests));
+        });
+        Ok(running)
+    }
+
+    fn udp_reply(&self, service: u32, invoke_id: u32, tags: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.write_u32::<LE>(UDP_MAGIC).unwrap();
+        v.write_u32::<LE>(invoke_id).unwrap();
+        v.write_u32::<LE>(service | UDP_REPLY).unwrap();
+        v.extend_from_slice(&self.netid.0);
+        v.write_u16::<LE>(10000).unwrap();
+        v.write_u32::<LE>(tags.len() as u32).unwrap();
+        for (tag, data) in tags {
+            v.write_u16::<LE>(*tag).unwrap();
+            v.write_u16::<LE>(data.len() as u16).unwrap();
+            v.extend_from_slice(data);
+        }
+        v
+    }
+
+    fn serve_udp(&self, sock: UdpSocket) {
+        let mut buf = [0; 3072];
+        while let Ok((len, addr)) = sock.recv_from(&mut buf) {
+            let msg = &buf[..len];
+            if len < 12 || LE::read_u32(msg) != UDP_MAGIC {
+                continue;
+            }
+            let invoke_id = LE::read_u32(&msg[4..]);
+            let reply = match LE::read_u32(&msg[8..]) {
+                // BCs don't answer the CX probe
+                UDP_IDENTIFY if !self.bc => {
+                    let mut name = self.name.as_bytes().to_vec();
+                    name.push(0);
+                    let (major, minor, build) = self.version;
+                    let version = [major, minor, build as u8, (build >> 8) as u8];
+                    self.udp_reply(UDP_IDENTIFY, invoke_id,
+                                   &[(TAG_COMPUTERNAME, &name), (TAG_TCVERSION, &version)])
+                }
+                UDP_ADDROUTE => self.udp_reply(UDP_ADDROUTE, invoke_id,
+                                               &[(TAG_STATUS, &0u32.to_le_bytes())]),
+                _ => continue,
+            };
+            let _ = sock.send_to(&reply, addr);
+        }
+    }
+
+    fn serve_bc_udp(&self, sock: UdpSocket) {
+        let mut buf = [0; 3072];
+        while let Ok((len, addr)) = sock.recv_from(&mut buf) {
+            let msg = &buf[..len];
+            if !self.bc || len != 16 || LE::read_u32(msg) != BC_SCAN_REQUEST {
+                continue;
+            }
+            // each requested area is echoed, followed by its data
+            let mut name = self.name.as_bytes()[..self.name.len().min(10)].to_vec();
+            name.resize(20, 0);
+            let mut reply = Vec::new();
+            reply.write_u32::<LE>(BC_SCAN_REPLY).unwrap();
+            reply.extend_from_slice(&msg[4..10]);
+            reply.extend_from_slice(&self.netid.0);
+            reply.extend_from_slice(&msg[10..16]);
+            reply.extend_from_slice(&name);
+            let _ = sock.send_to(&reply, addr);
+        }
+    }
+
+    fn serve_client(&self, mut sock: TcpStream, variables: &Variables,
+                    requests: &Mutex<Vec<AdsMessage>>) {
+        loop {
+            let mut frame = vec![0; 6];
+            if sock.read_exact(&mut frame).is_err() {
+                return;
+            }
+            let size = LE::read_u32(&frame[2..]) as usize;
+            frame.resize(6 + size, 0);
+            if sock.read_exact(&mut frame[6..]).is_err() {
+                return;
+            }
+            let request = match AdsMessage::try_new(frame) {
+                Ok(request) if !request.is_reply() => request,
+                _ => continue,
+            };
+            let data = self.handle_request(request.get_cmd(), request.get_data(), variables);
+            let reply = AdsMessage::new(request.get_source(), request.get_dest(),
+                                        request.get_cmd(), true, request.get_invoke_id(), &data);
+            requests.lock().unwrap().push(request);
+            if sock.write_all(&reply.0).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Return the reply data for a request, starting with the result.
+    fn handle_request(&self, cmd: u16, data: &[u8], variables: &Variables) -> Vec<u8> {
+        let mut reply = Vec::new();
+        match cmd {
+            GETSTATE => {
+                reply.write_u32::<LE>(0).unwrap();
+                reply.write_u16::<LE>(self.state).unwrap();
+                reply.write_u16::<LE>(0).unwrap();
+            }
+            DEVINFO => {
+                let (major, minor, build) = self.version;
+                reply.write_u32::<LE>(0).unwrap();
+                reply.extend_from_slice(&[major, minor]);
+                reply.write_u16::<LE>(build).unwrap();
+                let mut name = self.name.as_bytes()[..self.name.len().min(15)].to_vec();
+                name.resize(16, 0);
+                reply.extend_from_slice(&name);
+            }
+            READ | WRITE if data.len() >= 12 => {
+                let key = (LE::read_u32(data), LE::read_u32(&data[4..]));
+                let len = LE::read_u32(&data[8..]) as usize;
+                let mut variables = variables.lock().unwrap();
+                match variables.get_mut(&key) {
+                    None => reply.write_u32::<LE>(ADSERR_SYMBOLNOTFOUND).unwrap(),
+                    Some(value) if cmd == READ => {
+                        let mut value = value.clone();
+                        value.resize(len, 0);
+                        reply.write_u32::<LE>(0).unwrap();
+                        reply.write_u32::<LE>(len as u32).unwrap();
+                        reply.extend_from_slice(&value);
+                    }
+                    Some(value) => {
+                        *value = data[12..].to_vec();
+                        reply.write_u32::<LE>(0).unwrap();
+                    }
+                }
+            }
+            _ => reply.write_u32::<LE>(ADSERR_SRVNOTSUPP).unwrap(),
+        }
+        reply
+    }
+}
@@ -0,0 +1,168 @@
+// *****************************************************************************
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Enrico Faulhaber <enrico.faulhaber@frm2.tum.de>
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+
+mod mock;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ads::{AmsAddr, AmsNetId};
+use clap::Parser;
+
+use ads_forwarder::Options;
+use ads_forwarder::client::{self, AdsConn};
+use ads_forwarder::forwarder::{Beckhoff, BhType, Forwarder};
+use ads_forwarder::scanner::{Scan, Scanner};
+use ads_forwarder::util::{BECKHOFF_BC_UDP_PORT, BECKHOFF_TCP_PORT, BECKHOFF_UDP_PORT, WRITE};
+
+use mock::MockBeckhoff;
+
+const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+const CLIENT_NETID: AmsNetId = AmsNetId::new(10, 0, 0, 9, 1, 1);
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+
+fn client_addr() -> AmsAddr {
+    AmsAddr::new(CLIENT_NETID, 30000)
+}
+
+fn mock_with_variable() -> MockBeckhoff {
+    let mut mock = MockBeckhoff::default();
+    mock.variables.insert((0x4020, 0), vec![0; 4]);
+    mock
+}
+
+#[test]
+fn state_and_device_info() {
+    let mock = MockBeckhoff::default();
+    let (netid, name, version) = (mock.netid, mock.name.clone(), mock.version);
+    let running = mock.start(LOCALHOST).unwrap();
+    let mut conn = AdsConn::connect(running.tcp_addr, client_addr(), TIMEOUT).unwrap();
+    let target = AmsAddr::new(netid, 10000);
+    assert_eq!(client::read_state(&mut conn, &target).unwrap(), (5, 0));
+    assert_eq!(client::read_device_info(&mut conn, &target).unwrap(), (name, version));
+}
+
+#[test]
+fn write_round_trip() {
+    let running = mock_with_variable().start(LOCALHOST).unwrap();
+    let mut conn = AdsConn::connect(running.tcp_addr, client_addr(), TIMEOUT).unwrap();
+    let target = AmsAddr::new(MockBeckhoff::default().netid, 851);
+    client::write(&mut conn, &target, 0x4020, 0, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(running.variables.lock().unwrap()[&(0x4020, 0)], [1, 2, 3, 4]);
+    assert_eq!(client::read(&mut conn, &target, 0x4020, 0, 4).unwrap(), [1, 2, 3, 4]);
+
+    // the frame as the mock received it
+    let requests = running.requests.lock().unwrap();
+    let request = &requests[0];
+    assert_eq!(request.get_cmd(), WRITE);
+    assert_eq!(request.get_source(), client_addr());
+    assert_eq!(request.get_dest(), target);
+    assert_eq!(request.get_data(), [0x20, 0x40, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn write_error_is_typed() {
+    let running = mock_with_variable().start(LOCALHOST).unwrap();
+    let mut conn = AdsConn::connect(running.tcp_addr, client_addr(), TIMEOUT).unwrap();
+    let target = AmsAddr::new(MockBeckhoff::default().netid, 851);
+    let err = client::write(&mut conn, &target, 0x4020, 8, &[1]).unwrap_err();
+    assert_eq!(err.ads_code(), Some(0x710));
+}
+
+#[test]
+fn scan_finds_cx() {
+    let running = MockBeckhoff::default().start(LOCALHOST).unwrap();
+    let scanner = Scanner::new(false).ports(running.bc_udp_addr.port(), running.udp_addr.port(),
+                                            running.tcp_addr.port());
+    let found = scanner.scan(Scan::Address(LOCALHOST));
+    assert_eq!(found.len(), 1);
+    let bh = &found[0];
+    assert_eq!(bh.bh_addr, LOCALHOST);
+    assert_eq!(bh.netid, MockBeckhoff::default().netid);
+    assert_eq!(bh.name, "MOCK-CX");
+    assert_eq!(bh.version, Some((3, 1, 4024)));
+    assert!(bh.typ == BhType::CX3);
+}
+
+#[test]
+fn scan_finds_bc() {
+    let mock = MockBeckhoff { bc: true, name: "BC9120".into(), ..Default::default() };
+    let running = mock.start(LOCALHOST).unwrap();
+    let scanner = Scanner::new(false).ports(running.bc_udp_addr.port(), running.udp_addr.port(),
+                                            running.tcp_addr.port());
+    let found = scanner.scan(Scan::Address(LOCALHOST));
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].name, "BC9120");
+    assert_eq!(found[0].version, None);
+    assert!(found[0].typ == BhType::BC);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn scan_async_finds_cx() {
+    let running = MockBeckhoff::default().start(LOCALHOST).unwrap();
+    let scanner = Scanner::new(false).ports(running.bc_udp_addr.port(), running.udp_addr.port(),
+                                            running.tcp_addr.port());
+    let found = scanner.scan_async(Scan::Address(LOCALHOST)).await;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].netid, MockBeckhoff::default().netid);
+    assert_eq!(found[0].name, "MOCK-CX");
+    assert!(found[0].typ == BhType::CX3);
+}
+
+#[test]
+fn forwarder_relays_to_mock() {
+    // the forwarder connects to the standard ports, so give the mock its own
+    // loopback address
+    let bh_addr = Ipv4Addr::new(127, 0, 0, 3);
+    let mock = mock_with_variable();
+    let netid = mock.netid;
+    let running = mock.start_on(bh_addr, BECKHOFF_BC_UDP_PORT, BECKHOFF_UDP_PORT,
+                                BECKHOFF_TCP_PORT).unwrap();
+    let listen: SocketAddr = (Ipv4Addr::new(127, 0, 0, 4), BECKHOFF_TCP_PORT).into();
+    let listen_arg = listen.to_string();
+    let opts = Options::parse_from(["ads_forwarder", "--listen", listen_arg.as_str()]);
+    let bh = Beckhoff { if_addr: LOCALHOST, bh_addr, netid, typ: BhType::CX3,
+                        name: "MOCK-CX".into(), version: None, rtt: None };
+    thread::spawn(move || Forwarder::new(opts, bh).run());
+
+    let start = Instant::now();
+    let mut conn = loop {
+        match AdsConn::connect(listen, client_addr(), TIMEOUT) {
+            Ok(conn) => break conn,
+            Err(err) if start.elapsed() > TIMEOUT => panic!("forwarder not reachable: {err}"),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    };
+    let target = AmsAddr::new(netid, 851);
+    client::write(&mut conn, &target, 0x4020, 0, &[5, 6, 7, 8]).unwrap();
+    assert_eq!(client::read(&mut conn, &target, 0x4020, 0, 4).unwrap(), [5, 6, 7, 8]);
+
+    // the Beckhoff sees the client's requests from its virtual NetID
+    let requests = running.requests.lock().unwrap();
+    let sources: Vec<_> = requests.iter().map(|req| req.get_source_id()).collect();
+    assert_eq!(sources.len(), 2);
+    assert!(sources.iter().all(|&id| id != CLIENT_NETID && id == sources[0]));
+}